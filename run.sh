@@ -1,14 +1,15 @@
 #! /bin/bash
 set -eu
 
-# download mokuroku.csv
+# mokuroku.csvが無ければcollectに取得させる
+FETCH_MOKUROKU=()
 if [ ! -f mokuroku.csv ]; then
-  curl -#fL https://cyberjapandata.gsi.go.jp/xyz/experimental_rvrcl/mokuroku.csv.gz | gzip -d > mokuroku.csv
+  FETCH_MOKUROKU=(--fetch-mokuroku)
 fi
 
 # run docker container
 
-docker run --rm --name rnet -v "${PWD}:/data" -it ghcr.io/azishio/rnet:latest collect "$@"
+docker run --rm --name rnet -v "${PWD}:/data" -it ghcr.io/azishio/rnet:latest collect ${FETCH_MOKUROKU[@]+"${FETCH_MOKUROKU[@]}"} "$@"
 # -h や --help出会った場合は、ヘルプを表示して終了
 if [ "$1" = "-h" ] || [ "$1" = "--help" ]; then
   exit 0
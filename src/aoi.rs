@@ -0,0 +1,466 @@
+use std::path::Path;
+
+use anyhow::anyhow;
+use geojson::{GeoJson, Geometry, PolygonType, Value};
+use rustc_hash::FxHashMap;
+
+/// (経度, 緯度) [度]
+pub type LL = (f64, f64);
+
+/// ポリゴンの辺
+#[derive(Debug, Clone, Copy)]
+struct Edge {
+    /// 辺が属するポリゴンの番号
+    polygon: u32,
+    a: LL,
+    b: LL,
+}
+
+/// 処理対象とする範囲を表すポリゴンの集合
+///
+/// 辺を格子状のインデックスに登録しておき、点の内外判定や線分との交差判定では
+/// 問い合わせた範囲の格子に含まれる辺のみを調べる
+#[derive(Debug)]
+pub struct Aoi {
+    edges: Vec<Edge>,
+    min: LL,
+    max: LL,
+    /// 格子の分割数
+    nx: usize,
+    ny: usize,
+    /// 格子ごとの辺の番号
+    grid: Vec<Vec<u32>>,
+}
+
+impl Aoi {
+    /// GeoJSONファイルからPolygonとMultiPolygonを読み込む
+    /// Geometry, Feature, FeatureCollectionのいずれの形式でもよい
+    pub fn from_geojson_file(path: &Path) -> anyhow::Result<Self> {
+        let s = std::fs::read_to_string(path)
+            .map_err(|e| anyhow!("Failed to read AOI file at {:?}: {:#?}", path, e))?;
+        let geojson = s
+            .parse::<GeoJson>()
+            .map_err(|e| anyhow!("Failed to parse AOI file at {:?} as GeoJSON: {:#?}", path, e))?;
+
+        let geometries = match geojson {
+            GeoJson::Geometry(geometry) => vec![geometry],
+            GeoJson::Feature(feature) => feature.geometry.into_iter().collect(),
+            GeoJson::FeatureCollection(fc) => fc
+                .features
+                .into_iter()
+                .filter_map(|feature| feature.geometry)
+                .collect(),
+        };
+
+        let mut polygons = Vec::new();
+        for geometry in geometries {
+            collect_polygons(geometry, &mut polygons)?;
+        }
+
+        if polygons.is_empty() {
+            return Err(anyhow!("No Polygon or MultiPolygon found in AOI file at {:?}", path));
+        }
+
+        Ok(Self::new(polygons))
+    }
+
+    /// ポリゴン(外周と穴のリング)の一覧から作成する
+    pub fn new(polygons: Vec<Vec<Vec<LL>>>) -> Self {
+        let edges = polygons
+            .iter()
+            .enumerate()
+            .flat_map(|(i, rings)| {
+                rings.iter().flat_map(move |ring| {
+                    // リングが閉じていない場合も最後の点と最初の点を結ぶ
+                    let n = ring.len();
+                    (0..n)
+                        .map(move |j| (ring[j], ring[(j + 1) % n]))
+                        .filter(|(a, b)| a != b)
+                        .map(move |(a, b)| Edge {
+                            polygon: i as u32,
+                            a,
+                            b,
+                        })
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let (min, max) = edges.iter().fold(
+            ((f64::MAX, f64::MAX), (f64::MIN, f64::MIN)),
+            |(min, max), edge| {
+                (
+                    (min.0.min(edge.a.0).min(edge.b.0), min.1.min(edge.a.1).min(edge.b.1)),
+                    (max.0.max(edge.a.0).max(edge.b.0), max.1.max(edge.a.1).max(edge.b.1)),
+                )
+            },
+        );
+
+        let n = ((edges.len() as f64).sqrt() as usize).clamp(1, 1024);
+        let mut aoi = Self {
+            edges,
+            min,
+            max,
+            nx: n,
+            ny: n,
+            grid: vec![Vec::new(); n * n],
+        };
+
+        for (i, edge) in aoi.edges.iter().enumerate() {
+            let (x0, y0) = aoi.cell_of(min_ll(edge.a, edge.b));
+            let (x1, y1) = aoi.cell_of(max_ll(edge.a, edge.b));
+            for y in y0..=y1 {
+                for x in x0..=x1 {
+                    aoi.grid[y * aoi.nx + x].push(i as u32);
+                }
+            }
+        }
+
+        aoi
+    }
+
+    /// 範囲全体を囲む矩形 (最小経度, 最大経度, 最小緯度, 最大緯度)
+    pub fn bounds(&self) -> (f64, f64, f64, f64) {
+        (self.min.0, self.max.0, self.min.1, self.max.1)
+    }
+
+    /// 点が範囲内にあるか (境界上の点は範囲内とする)
+    pub fn contains(&self, p: LL) -> bool {
+        if p.0 < self.min.0 || self.max.0 < p.0 || p.1 < self.min.1 || self.max.1 < p.1 {
+            return false;
+        }
+
+        // 点から東向きに伸ばした半直線と交差する辺の数をポリゴンごとに数える
+        let (cx, cy) = self.cell_of(p);
+        let mut parity = FxHashMap::<u32, bool>::default();
+        for i in self.edges_in_cells((cx, cy), (self.nx - 1, cy)) {
+            let Edge { polygon, a, b } = self.edges[i];
+            if is_on_segment(p, a, b) {
+                return true;
+            }
+            if (a.1 > p.1) != (b.1 > p.1) {
+                let x = a.0 + (p.1 - a.1) / (b.1 - a.1) * (b.0 - a.0);
+                if p.0 < x {
+                    *parity.entry(polygon).or_default() ^= true;
+                }
+            }
+        }
+
+        parity.values().any(|inside| *inside)
+    }
+
+    /// 矩形の一部でも範囲と重なるか
+    pub fn intersects_rect(&self, min: LL, max: LL) -> bool {
+        if max.0 < self.min.0 || self.max.0 < min.0 || max.1 < self.min.1 || self.max.1 < min.1 {
+            return false;
+        }
+
+        let corners = [min, (max.0, min.1), max, (min.0, max.1)];
+        if corners.iter().any(|p| self.contains(*p)) {
+            return true;
+        }
+
+        // 矩形の内側に辺の端点があるか、矩形の辺と交差する辺があれば重なっている
+        let inside_rect = |p: LL| min.0 <= p.0 && p.0 <= max.0 && min.1 <= p.1 && p.1 <= max.1;
+        self.edges_in_cells(self.cell_of(min), self.cell_of(max))
+            .any(|i| {
+                let edge = self.edges[i];
+                inside_rect(edge.a)
+                    || inside_rect(edge.b)
+                    || (0..4).any(|j| {
+                        segment_intersection(edge.a, edge.b, corners[j], corners[(j + 1) % 4])
+                            .is_some()
+                    })
+            })
+    }
+
+    /// 矩形全体が範囲に含まれるか (境界に接している場合も含まれるとする)
+    pub fn contains_rect(&self, min: LL, max: LL) -> bool {
+        let corners = [min, (max.0, min.1), max, (min.0, max.1)];
+        if !corners.iter().all(|p| self.contains(*p)) {
+            return false;
+        }
+
+        // 4隅が範囲内でも、穴や凹んだ部分の辺が矩形の内側に入り込んでいれば含まれない
+        // 辺のうち矩形に含まれる部分の中点が矩形の境界上にあれば、その部分は全て境界上にある
+        let strictly_inside_rect = |p: LL| min.0 < p.0 && p.0 < max.0 && min.1 < p.1 && p.1 < max.1;
+        !self
+            .edges_in_cells(self.cell_of(min), self.cell_of(max))
+            .any(|i| {
+                let edge = self.edges[i];
+                clip_segment_to_rect(edge.a, edge.b, min, max)
+                    .is_some_and(|(t0, t1)| strictly_inside_rect(lerp(edge.a, edge.b, (t0 + t1) / 2.)))
+            })
+    }
+
+    /// 折れ線を範囲の境界で切断し、範囲内にある部分のみを返す
+    /// 境界との交点は新たな頂点として追加される
+    pub fn clip_line(&self, line: &[LL]) -> Vec<Vec<LL>> {
+        let mut pieces = Vec::new();
+        let mut current = Vec::<LL>::new();
+
+        for segment in line.windows(2) {
+            let (p, q) = (segment[0], segment[1]);
+
+            // 線分と境界の交点で線分を分割する
+            let mut ts = self
+                .edges_in_cells(self.cell_of(min_ll(p, q)), self.cell_of(max_ll(p, q)))
+                .filter_map(|i| segment_intersection(p, q, self.edges[i].a, self.edges[i].b))
+                .filter(|t| 0. < *t && *t < 1.)
+                .collect::<Vec<_>>();
+            ts.push(0.);
+            ts.push(1.);
+            ts.sort_by(f64::total_cmp);
+            ts.dedup();
+
+            for t in ts.windows(2) {
+                let start = lerp(p, q, t[0]);
+                let end = lerp(p, q, t[1]);
+
+                // 分割した線分の中点が範囲内であれば、その線分は範囲内にある
+                if self.contains(lerp(p, q, (t[0] + t[1]) / 2.)) {
+                    if current.is_empty() {
+                        current.push(start);
+                    }
+                    current.push(end);
+                } else if !current.is_empty() {
+                    pieces.push(std::mem::take(&mut current));
+                }
+            }
+        }
+
+        if current.len() >= 2 {
+            pieces.push(current);
+        }
+
+        pieces
+    }
+
+    /// 点が含まれる格子の番号
+    fn cell_of(&self, p: LL) -> (usize, usize) {
+        let fx = (p.0 - self.min.0) / (self.max.0 - self.min.0).max(f64::EPSILON);
+        let fy = (p.1 - self.min.1) / (self.max.1 - self.min.1).max(f64::EPSILON);
+
+        (
+            ((fx * self.nx as f64).max(0.) as usize).min(self.nx - 1),
+            ((fy * self.ny as f64).max(0.) as usize).min(self.ny - 1),
+        )
+    }
+
+    /// 2つの格子を対角とする範囲に含まれる辺の番号(重複なし)
+    fn edges_in_cells(&self, (x0, y0): (usize, usize), (x1, y1): (usize, usize)) -> impl Iterator<Item = usize> {
+        let mut ids = (y0..=y1)
+            .flat_map(|y| (x0..=x1).map(move |x| (x, y)))
+            .flat_map(|(x, y)| self.grid[y * self.nx + x].iter().copied())
+            .collect::<Vec<_>>();
+        ids.sort_unstable();
+        ids.dedup();
+
+        ids.into_iter().map(|i| i as usize)
+    }
+}
+
+/// GeometryからPolygonを取り出す
+fn collect_polygons(geometry: Geometry, polygons: &mut Vec<Vec<Vec<LL>>>) -> anyhow::Result<()> {
+    let to_rings = |polygon: PolygonType| {
+        polygon
+            .into_iter()
+            .map(|ring| ring.into_iter().map(|p| (p[0], p[1])).collect::<Vec<_>>())
+            .collect::<Vec<_>>()
+    };
+
+    match geometry.value {
+        Value::Polygon(polygon) => polygons.push(to_rings(polygon)),
+        Value::MultiPolygon(multi) => polygons.extend(multi.into_iter().map(to_rings)),
+        Value::GeometryCollection(geometries) => {
+            for geometry in geometries {
+                collect_polygons(geometry, polygons)?;
+            }
+        }
+        other => {
+            return Err(anyhow!(
+                "Unexpected geometry type {:?} in AOI file. Only Polygon and MultiPolygon are supported.",
+                other.type_name()
+            ))
+        }
+    }
+
+    Ok(())
+}
+
+/// 点pが線分ab上にあるか
+fn is_on_segment(p: LL, a: LL, b: LL) -> bool {
+    let cross = (b.0 - a.0) * (p.1 - a.1) - (b.1 - a.1) * (p.0 - a.0);
+    cross == 0.
+        && a.0.min(b.0) <= p.0
+        && p.0 <= a.0.max(b.0)
+        && a.1.min(b.1) <= p.1
+        && p.1 <= a.1.max(b.1)
+}
+
+/// 線分pqと線分abの交点を、pqの媒介変数tとして返す
+fn segment_intersection(p: LL, q: LL, a: LL, b: LL) -> Option<f64> {
+    let r = (q.0 - p.0, q.1 - p.1);
+    let s = (b.0 - a.0, b.1 - a.1);
+    let denom = r.0 * s.1 - r.1 * s.0;
+    if denom == 0. {
+        return None;
+    }
+
+    let ap = (a.0 - p.0, a.1 - p.1);
+    let t = (ap.0 * s.1 - ap.1 * s.0) / denom;
+    let u = (ap.0 * r.1 - ap.1 * r.0) / denom;
+
+    ((0. ..=1.).contains(&t) && (0. ..=1.).contains(&u)).then_some(t)
+}
+
+/// 線分abのうち矩形に含まれる部分を、abの媒介変数tの範囲として返す (Liang-Barsky)
+fn clip_segment_to_rect(a: LL, b: LL, min: LL, max: LL) -> Option<(f64, f64)> {
+    let d = (b.0 - a.0, b.1 - a.1);
+    let mut t0 = 0_f64;
+    let mut t1 = 1_f64;
+    for (p, q) in [
+        (-d.0, a.0 - min.0),
+        (d.0, max.0 - a.0),
+        (-d.1, a.1 - min.1),
+        (d.1, max.1 - a.1),
+    ] {
+        if p == 0. {
+            if q < 0. {
+                return None;
+            }
+        } else if p < 0. {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+
+    (t0 <= t1).then_some((t0, t1))
+}
+
+/// 線分pq上の点 (t = 0, 1では元の座標をそのまま返す)
+fn lerp(p: LL, q: LL, t: f64) -> LL {
+    if t == 0. {
+        p
+    } else if t == 1. {
+        q
+    } else {
+        (p.0 + (q.0 - p.0) * t, p.1 + (q.1 - p.1) * t)
+    }
+}
+
+fn min_ll(a: LL, b: LL) -> LL {
+    (a.0.min(b.0), a.1.min(b.1))
+}
+
+fn max_ll(a: LL, b: LL) -> LL {
+    (a.0.max(b.0), a.1.max(b.1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// (0, 0)-(10, 10)の正方形から(4, 4)-(6, 6)の穴を除いた範囲
+    fn square_with_hole() -> Aoi {
+        Aoi::new(vec![vec![
+            vec![(0., 0.), (10., 0.), (10., 10.), (0., 10.), (0., 0.)],
+            vec![(4., 4.), (6., 4.), (6., 6.), (4., 6.), (4., 4.)],
+        ]])
+    }
+
+    #[test]
+    fn contains_point() {
+        let aoi = square_with_hole();
+        assert!(aoi.contains((1., 1.)));
+        assert!(aoi.contains((8., 5.)));
+        assert!(!aoi.contains((5., 5.)));
+        assert!(!aoi.contains((11., 5.)));
+        assert!(!aoi.contains((-1., -1.)));
+    }
+
+    #[test]
+    fn contains_point_on_boundary() {
+        let aoi = square_with_hole();
+        assert!(aoi.contains((0., 5.)));
+        assert!(aoi.contains((10., 10.)));
+        assert!(aoi.contains((4., 5.)));
+    }
+
+    #[test]
+    fn rect_inside() {
+        let aoi = square_with_hole();
+        assert!(aoi.intersects_rect((1., 1.), (2., 2.)));
+        assert!(aoi.contains_rect((1., 1.), (2., 2.)));
+        // 境界に接している場合も含まれる
+        assert!(aoi.contains_rect((0., 0.), (4., 4.)));
+    }
+
+    #[test]
+    fn rect_outside() {
+        let aoi = square_with_hole();
+        assert!(!aoi.intersects_rect((20., 20.), (21., 21.)));
+        assert!(!aoi.contains_rect((20., 20.), (21., 21.)));
+    }
+
+    #[test]
+    fn rect_inside_hole() {
+        let aoi = square_with_hole();
+        assert!(!aoi.intersects_rect((4.5, 4.5), (5.5, 5.5)));
+        assert!(!aoi.contains_rect((4.5, 4.5), (5.5, 5.5)));
+    }
+
+    #[test]
+    fn rect_crossing_boundary() {
+        let aoi = square_with_hole();
+        assert!(aoi.intersects_rect((9., 9.), (11., 11.)));
+        assert!(!aoi.contains_rect((9., 9.), (11., 11.)));
+    }
+
+    #[test]
+    fn rect_surrounding_hole() {
+        // 4隅は範囲内だが、内側に穴がある
+        let aoi = square_with_hole();
+        assert!(aoi.intersects_rect((3., 3.), (7., 7.)));
+        assert!(!aoi.contains_rect((3., 3.), (7., 7.)));
+    }
+
+    #[test]
+    fn rect_covering_aoi() {
+        // 矩形の4隅はいずれも範囲外だが、範囲全体を含む
+        let aoi = square_with_hole();
+        assert!(aoi.intersects_rect((-1., -1.), (11., 11.)));
+        assert!(!aoi.contains_rect((-1., -1.), (11., 11.)));
+    }
+
+    #[test]
+    fn clip_segment_crossing_boundary() {
+        let aoi = square_with_hole();
+        assert_eq!(aoi.clip_line(&[(-5., 1.), (5., 1.)]), vec![vec![(0., 1.), (5., 1.)]]);
+    }
+
+    #[test]
+    fn clip_segment_crossing_hole() {
+        let aoi = square_with_hole();
+        assert_eq!(
+            aoi.clip_line(&[(1., 5.), (9., 5.)]),
+            vec![vec![(1., 5.), (4., 5.)], vec![(6., 5.), (9., 5.)]]
+        );
+    }
+
+    #[test]
+    fn clip_polyline_leaving_and_entering() {
+        let aoi = square_with_hole();
+        assert_eq!(
+            aoi.clip_line(&[(2., 2.), (2., 12.), (8., 12.), (8., 8.)]),
+            vec![vec![(2., 2.), (2., 10.)], vec![(8., 10.), (8., 8.)]]
+        );
+    }
+
+    #[test]
+    fn clip_line_outside() {
+        let aoi = square_with_hole();
+        assert!(aoi.clip_line(&[(11., 0.), (11., 10.)]).is_empty());
+        assert!(aoi.clip_line(&[(4.5, 5.), (5.5, 5.)]).is_empty());
+    }
+}
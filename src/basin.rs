@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use indicatif::ProgressBar;
+
+use crate::graph::{find_node_id_column, is_river_node, parse_node_id, RiverGraph, Table};
+use crate::NetworkArgs;
+
+/// ノード・リンク・river_basin.csvの水系IDの列名
+pub(crate) const BASIN_ID_COLUMN: &str = "basin_id:long";
+
+/// 水系ごとの集計
+struct Basin {
+    /// 0から始まる水系の番号 (最小のノードIDの順)
+    id: usize,
+    /// 水系に含まれる最小のノードID
+    min_node_id: usize,
+    node_count: usize,
+    link_count: usize,
+    /// リンクの長さの合計[m]
+    length: f64,
+    /// (最小経度, 最小緯度, 最大経度, 最大緯度)
+    bounds: (f64, f64, f64, f64),
+    /// 最も低いノード
+    lowest: Option<(usize, f32)>,
+}
+
+/// `network basin`サブコマンド用の関数
+/// リンクで繋がったノードを1つの水系とし、ノードとリンクに`basin_id:long`を付与して、水系ごとの集計をriver_basin.csvに書き出す
+/// `basin_id`は水系に含まれる最小のノードIDの順に0から振る番号とする
+/// (ノードIDは`--id-zoom 24`ではi64に収まらないため、そのままでは`long`として読み込めない)
+pub fn label_basins(args: &NetworkArgs) {
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let nodes_path = Path::new(&args.nodes);
+    let links_path = Path::new(&args.links);
+
+    spinner.set_message("Reading nodes and links...");
+    let mut nodes = Table::read(nodes_path);
+    let mut links = Table::read(links_path);
+    let graph = RiverGraph::new(&nodes, &links);
+
+    spinner.set_message("Finding connected components...");
+    let (component, count) = graph.components();
+    let mut basins = (0..count)
+        .map(|_| Basin {
+            id: 0,
+            min_node_id: usize::MAX,
+            node_count: 0,
+            link_count: 0,
+            length: 0.,
+            bounds: (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+            lowest: None,
+        })
+        .collect::<Vec<_>>();
+
+    for node in 0..graph.node_count() {
+        let basin = &mut basins[component[node]];
+        let (long, lat) = graph.coords[node];
+        basin.min_node_id = basin.min_node_id.min(graph.ids[node]);
+        basin.node_count += 1;
+        basin.bounds = (
+            basin.bounds.0.min(long),
+            basin.bounds.1.min(lat),
+            basin.bounds.2.max(long),
+            basin.bounds.3.max(lat),
+        );
+        if let Some(altitude) = graph.altitudes[node] {
+            if basin.lowest.is_none_or(|(_, lowest)| altitude < lowest) {
+                basin.lowest = Some((graph.ids[node], altitude));
+            }
+        }
+    }
+    for link in &graph.links {
+        let basin = &mut basins[component[link.start]];
+        basin.link_count += 1;
+        basin.length += link.length;
+    }
+
+    // 出力が常に同じになるよう、最小のノードIDの順に番号を振る
+    let mut by_min_node_id = (0..count).collect::<Vec<_>>();
+    by_min_node_id.sort_unstable_by_key(|c| basins[*c].min_node_id);
+    for (id, c) in by_min_node_id.into_iter().enumerate() {
+        basins[c].id = id;
+    }
+
+    spinner.set_message("Writing basin IDs...");
+    let id_column = find_node_id_column(&nodes);
+    let node_basins = nodes
+        .rows
+        .iter()
+        .map(|row| {
+            if !is_river_node(&nodes, row) {
+                return String::new();
+            }
+            let node = graph.index[&parse_node_id(&row[id_column])];
+            basins[component[node]].id.to_string()
+        })
+        .collect::<Vec<_>>();
+    nodes.set_column(BASIN_ID_COLUMN, node_basins);
+    nodes.write(nodes_path);
+
+    let mut link_basins = vec![String::new(); links.rows.len()];
+    for link in &graph.links {
+        link_basins[link.row] = basins[component[link.start]].id.to_string();
+    }
+    links.set_column(BASIN_ID_COLUMN, link_basins);
+    links.write(links_path);
+
+    // 大きい水系から順に書き出す
+    basins.sort_by(|a, b| b.node_count.cmp(&a.node_count).then(a.id.cmp(&b.id)));
+    let summary = Table {
+        header: [
+            BASIN_ID_COLUMN,
+            "min_node_id",
+            "node_count",
+            "link_count",
+            "length",
+            "min_longitude",
+            "min_latitude",
+            "max_longitude",
+            "max_latitude",
+            "lowest_node_id",
+            "lowest_altitude",
+        ]
+            .map(str::to_string)
+            .to_vec(),
+        rows: basins
+            .iter()
+            .map(|basin| {
+                let (min_long, min_lat, max_long, max_lat) = basin.bounds;
+                vec![
+                    basin.id.to_string(),
+                    basin.min_node_id.to_string(),
+                    basin.node_count.to_string(),
+                    basin.link_count.to_string(),
+                    format!("{:.3}", basin.length),
+                    min_long.to_string(),
+                    min_lat.to_string(),
+                    max_long.to_string(),
+                    max_lat.to_string(),
+                    basin.lowest.map(|(id, _)| id.to_string()).unwrap_or_default(),
+                    basin.lowest.map(|(_, altitude)| altitude.to_string()).unwrap_or_default(),
+                ]
+            })
+            .collect(),
+    };
+    let summary_path = links_path.with_file_name("river_basin.csv");
+    summary.write(&summary_path);
+
+    spinner.finish_with_message(format!(
+        "Labeled {} basins. Summary written to {:?}",
+        count, summary_path
+    ));
+}
@@ -0,0 +1,393 @@
+use std::path::Path;
+
+use clap::ValueEnum;
+use indicatif::ProgressBar;
+
+use crate::graph::{find_node_id_column, is_river_node, parse_node_id, require_directed, RiverGraph, Table};
+use crate::BurnArgs;
+
+/// 補正前の標高の列名
+const ALTITUDE_RAW_COLUMN: &str = "altitude_raw:float";
+
+/// 標高の補正方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum BurnMethod {
+    /// 上流より高いノードを上流の標高まで削る (橋や堤防を取り除く)
+    Breach,
+    /// 下流より低いノードを下流の標高まで埋める (窪地を取り除く)
+    Fill,
+    /// 流下方向に単調減少する標高のうち、元の標高との二乗誤差が最小のものを求める
+    Isotonic,
+}
+
+/// `network burn`サブコマンド用の関数
+/// FLOWS_TOリンクに沿って下流の標高が上流を超えないようにノードの標高を補正し、
+/// 補正前の標高を`altitude_raw:float`に、補正後の標高を`altitude:float`に書き込んで、リンクの標高差と勾配を求め直す
+/// 既に`altitude_raw:float`がある場合はそれを補正前の標高として補正し直す
+pub fn burn_altitudes(args: &BurnArgs) {
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let BurnArgs { network, method } = args;
+    let nodes_path = Path::new(&network.nodes);
+    let links_path = Path::new(&network.links);
+
+    spinner.set_message("Reading nodes and links...");
+    let mut nodes = Table::read(nodes_path);
+    let mut links = Table::read(links_path);
+    require_directed(&links);
+
+    // 補正し直す場合は、補正前の標高を読み込む
+    let altitude_column = nodes.expect_column("altitude:float");
+    let raw_altitudes = match nodes.column(ALTITUDE_RAW_COLUMN) {
+        Some(raw_column) => nodes.rows.iter().map(|row| row[raw_column].clone()).collect::<Vec<_>>(),
+        None => nodes.rows.iter().map(|row| row[altitude_column].clone()).collect::<Vec<_>>(),
+    };
+    for (row, raw) in nodes.rows.iter_mut().zip(&raw_altitudes) {
+        row[altitude_column] = raw.clone();
+    }
+    let mut graph = RiverGraph::new(&nodes, &links);
+
+    spinner.set_message("Sorting nodes from upstream to downstream...");
+    let topological = graph.topological_order();
+
+    spinner.set_message("Correcting altitudes...");
+    let corrected = match method {
+        BurnMethod::Breach => breach(&graph, &topological),
+        BurnMethod::Fill => fill(&graph, &topological),
+        BurnMethod::Isotonic => isotonic(&graph, &topological),
+    };
+
+    spinner.set_message("Writing nodes...");
+    let id_column = find_node_id_column(&nodes);
+    let mut changed = 0;
+    let mut max_change = 0_f32;
+    let altitudes = nodes
+        .rows
+        .iter()
+        .map(|row| {
+            if !is_river_node(&nodes, row) {
+                return row[altitude_column].clone();
+            }
+            let node = graph.index[&parse_node_id(&row[id_column])];
+            match (graph.altitudes[node], corrected[node]) {
+                (Some(raw), Some(altitude)) => {
+                    if altitude != raw {
+                        changed += 1;
+                        max_change = max_change.max((altitude - raw).abs());
+                    }
+                    altitude.to_string()
+                }
+                _ => row[altitude_column].clone(),
+            }
+        })
+        .collect::<Vec<_>>();
+    nodes.set_column(ALTITUDE_RAW_COLUMN, raw_altitudes);
+    nodes.set_column("altitude:float", altitudes);
+    nodes.write(nodes_path);
+
+    // リンクの標高差と勾配を補正後の標高で求め直す
+    spinner.set_message("Writing links...");
+    for (altitude, corrected) in graph.altitudes.iter_mut().zip(corrected) {
+        *altitude = corrected.or(*altitude);
+    }
+    graph.write_link_geometry(&mut links, &vec![false; graph.links.len()]);
+    links.write(links_path);
+
+    spinner.finish_with_message(format!(
+        "Corrected {} of {} node altitudes (max change {:.2} m, {} nodes in or below cycles left unchanged)",
+        changed,
+        graph.node_count(),
+        max_change,
+        graph.node_count() - topological.len(),
+    ));
+}
+
+/// 上流から順に、上流のノードの補正後の標高を超えないように削る
+/// 標高が無いノードは上流の標高をそのまま下流に伝える
+fn breach(graph: &RiverGraph, topological: &[usize]) -> Vec<Option<f32>> {
+    let mut corrected = graph.altitudes.clone();
+    // 標高が無いノードも含め、ノードの標高の上限
+    let mut levels = vec![None::<f32>; graph.node_count()];
+
+    for &node in topological {
+        let upper = graph
+            .incoming(node)
+            .filter_map(|link| levels[graph.links[link].start])
+            .reduce(f32::min);
+        corrected[node] = match (graph.altitudes[node], upper) {
+            (Some(altitude), Some(upper)) => Some(altitude.min(upper)),
+            (altitude, _) => altitude,
+        };
+        levels[node] = corrected[node].or(upper);
+    }
+
+    corrected
+}
+
+/// 下流から順に、下流のノードの補正後の標高を下回らないように埋める
+/// 標高が無いノードは下流の標高をそのまま上流に伝える
+fn fill(graph: &RiverGraph, topological: &[usize]) -> Vec<Option<f32>> {
+    let mut corrected = graph.altitudes.clone();
+    let mut levels = vec![None::<f32>; graph.node_count()];
+
+    for &node in topological.iter().rev() {
+        let lower = graph
+            .outgoing(node)
+            .filter_map(|link| levels[graph.links[link].end])
+            .reduce(f32::max);
+        corrected[node] = match (graph.altitudes[node], lower) {
+            (Some(altitude), Some(lower)) => Some(altitude.max(lower)),
+            (altitude, _) => altitude,
+        };
+        levels[node] = corrected[node].or(lower);
+    }
+
+    corrected
+}
+
+/// 分岐・合流の無いノードの連なりごとに単調減少の回帰(Pool Adjacent Violators)を行い、
+/// 上流の連なりの末端の標高を上限として切り詰める
+fn isotonic(graph: &RiverGraph, topological: &[usize]) -> Vec<Option<f32>> {
+    let mut corrected = graph.altitudes.clone();
+    let mut levels = vec![None::<f32>; graph.node_count()];
+    let mut sorted = vec![false; graph.node_count()];
+    for &node in topological {
+        sorted[node] = true;
+    }
+
+    // 1本だけ流れ込み、その上流が1本だけ流れ出るノードは連なりの途中とする
+    let single_upstream = |node: usize| {
+        let mut incoming = graph.incoming(node);
+        match (incoming.next(), incoming.next()) {
+            (Some(link), None) => {
+                let upstream = graph.links[link].start;
+                (graph.outgoing(upstream).count() == 1).then_some(upstream)
+            }
+            _ => None,
+        }
+    };
+
+    for &head in topological {
+        if single_upstream(head).is_some() {
+            continue;
+        }
+
+        // 連なりを下流へ辿る
+        let mut chain = vec![head];
+        let mut node = head;
+        loop {
+            let mut outgoing = graph.outgoing(node);
+            let (Some(link), None) = (outgoing.next(), outgoing.next()) else {
+                break;
+            };
+            let next = graph.links[link].end;
+            if !sorted[next] || single_upstream(next) != Some(node) {
+                break;
+            }
+            chain.push(next);
+            node = next;
+        }
+
+        let upper = graph
+            .incoming(head)
+            .filter_map(|link| levels[graph.links[link].start])
+            .reduce(f32::min);
+
+        let values = chain
+            .iter()
+            .filter_map(|node| graph.altitudes[*node].map(|altitude| altitude as f64))
+            .collect::<Vec<_>>();
+        let mut fitted = decreasing_regression(&values).into_iter();
+
+        let mut level = upper;
+        for &node in &chain {
+            if graph.altitudes[node].is_some() {
+                let altitude = fitted.next().unwrap() as f32;
+                corrected[node] = Some(level.map_or(altitude, |level| altitude.min(level)));
+            }
+            level = corrected[node].or(level);
+            levels[node] = level;
+        }
+    }
+
+    corrected
+}
+
+/// 単調減少の制約の下で二乗誤差が最小となる値を求める (Pool Adjacent Violators)
+fn decreasing_regression(values: &[f64]) -> Vec<f64> {
+    // (平均, 個数)のブロック
+    let mut blocks: Vec<(f64, usize)> = Vec::new();
+    for &value in values {
+        blocks.push((value, 1));
+        // 下流のブロックの方が高ければ、上流のブロックとまとめる
+        while blocks.len() >= 2 && blocks[blocks.len() - 1].0 > blocks[blocks.len() - 2].0 {
+            let (mean2, count2) = blocks.pop().unwrap();
+            let (mean1, count1) = blocks.pop().unwrap();
+            let count = count1 + count2;
+            blocks.push(((mean1 * count1 as f64 + mean2 * count2 as f64) / count as f64, count));
+        }
+    }
+
+    blocks
+        .into_iter()
+        .flat_map(|(mean, count)| std::iter::repeat_n(mean, count))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::*;
+    use crate::NetworkArgs;
+
+    /// テスト用の一時ディレクトリに書き出したネットワーク (破棄時にディレクトリごと削除する)
+    struct TempNetwork {
+        dir: PathBuf,
+    }
+
+    impl TempNetwork {
+        fn args(&self) -> NetworkArgs {
+            NetworkArgs {
+                nodes: self.dir.join("river_node.csv").to_string_lossy().to_string(),
+                links: self.dir.join("river_link.csv").to_string_lossy().to_string(),
+            }
+        }
+    }
+
+    impl Drop for TempNetwork {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    /// ノード(ID, 標高)とリンク(上流, 下流)を一時ディレクトリに書き出す
+    fn write_network(name: &str, nodes: &[(usize, &str)], links: &[(usize, usize)]) -> TempNetwork {
+        let dir = std::env::temp_dir().join(format!("rnet_burn_{}_{}", name, std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        let nodes_csv = std::iter::once(
+            "hilbert18:ID,location:point{crs:WGS-84},altitude:float,altitude_source:int,:LABEL".to_string(),
+        )
+            .chain(nodes.iter().map(|(id, altitude)| {
+                format!("{id},\"{{longitude:135.{id},latitude:35.0}}\",{altitude},1,RiverNode")
+            }))
+            .collect::<Vec<_>>()
+            .join("\n");
+        let links_csv = std::iter::once(
+            ":START_ID,:END_ID,:TYPE,length:float,drop:float,slope:float,length3d:float,bearing:float".to_string(),
+        )
+            .chain(links.iter().map(|(start, end)| format!("{start},{end},FLOWS_TO,100,,,,")))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        std::fs::write(dir.join("river_node.csv"), nodes_csv + "\n").unwrap();
+        std::fs::write(dir.join("river_link.csv"), links_csv + "\n").unwrap();
+
+        TempNetwork { dir }
+    }
+
+    /// 補正を実行し、ノードごとの(altitude:float, altitude_raw:float)を返す
+    fn burn(network: &TempNetwork, method: BurnMethod) -> Vec<(Option<f32>, Option<f32>)> {
+        let network = network.args();
+        let nodes_path = PathBuf::from(&network.nodes);
+        burn_altitudes(&BurnArgs { network, method });
+
+        let nodes = Table::read(&nodes_path);
+        let altitude_column = nodes.expect_column("altitude:float");
+        let raw_column = nodes.expect_column(ALTITUDE_RAW_COLUMN);
+        nodes
+            .rows
+            .iter()
+            .map(|row| (row[altitude_column].parse().ok(), row[raw_column].parse().ok()))
+            .collect()
+    }
+
+    /// 1 → 2 → 3 → 4 の連なり (2が橋、3が窪地)
+    fn chain(name: &str) -> TempNetwork {
+        write_network(
+            name,
+            &[(1, "10"), (2, "12"), (3, "8"), (4, "9")],
+            &[(1, 2), (2, 3), (3, 4)],
+        )
+    }
+
+    /// 1 → 3, 2 → 3 の合流と 3 → 4
+    fn confluence(name: &str) -> TempNetwork {
+        write_network(
+            name,
+            &[(1, "10"), (2, "5"), (3, "7"), (4, "6")],
+            &[(1, 3), (2, 3), (3, 4)],
+        )
+    }
+
+    fn altitudes(values: &[f32]) -> Vec<Option<f32>> {
+        values.iter().copied().map(Some).collect()
+    }
+
+    fn assert_burned(result: &[(Option<f32>, Option<f32>)], expected: &[f32], raw: &[f32]) {
+        let (actual, actual_raw): (Vec<_>, Vec<_>) = result.iter().copied().unzip();
+        assert_eq!(actual, altitudes(expected));
+        assert_eq!(actual_raw, altitudes(raw));
+    }
+
+    #[test]
+    fn breach_chain() {
+        let result = burn(&chain("breach_chain"), BurnMethod::Breach);
+        assert_burned(&result, &[10., 10., 8., 8.], &[10., 12., 8., 9.]);
+    }
+
+    #[test]
+    fn fill_chain() {
+        let result = burn(&chain("fill_chain"), BurnMethod::Fill);
+        assert_burned(&result, &[12., 12., 9., 9.], &[10., 12., 8., 9.]);
+    }
+
+    #[test]
+    fn isotonic_chain() {
+        let result = burn(&chain("isotonic_chain"), BurnMethod::Isotonic);
+        assert_burned(&result, &[11., 11., 8.5, 8.5], &[10., 12., 8., 9.]);
+    }
+
+    #[test]
+    fn breach_confluence() {
+        // 合流点は低い方の支流の標高で切り詰められる
+        let result = burn(&confluence("breach_confluence"), BurnMethod::Breach);
+        assert_burned(&result, &[10., 5., 5., 5.], &[10., 5., 7., 6.]);
+    }
+
+    #[test]
+    fn fill_confluence() {
+        // 低い方の支流は合流点の標高まで埋められる
+        let result = burn(&confluence("fill_confluence"), BurnMethod::Fill);
+        assert_burned(&result, &[10., 7., 7., 6.], &[10., 5., 7., 6.]);
+    }
+
+    #[test]
+    fn isotonic_confluence() {
+        // 合流点から下流の連なりは、上流の連なりの末端の標高で切り詰められる
+        let result = burn(&confluence("isotonic_confluence"), BurnMethod::Isotonic);
+        assert_burned(&result, &[10., 5., 5., 5.], &[10., 5., 7., 6.]);
+    }
+
+    #[test]
+    fn breach_through_missing_altitude() {
+        // 標高が無いノードは上流の標高を下流に伝える
+        let network = write_network(
+            "breach_missing",
+            &[(1, "5"), (2, ""), (3, "7")],
+            &[(1, 2), (2, 3)],
+        );
+        let result = burn(&network, BurnMethod::Breach);
+        assert_eq!(result, vec![(Some(5.), Some(5.)), (None, None), (Some(5.), Some(7.))]);
+    }
+
+    #[test]
+    fn rerun_corrects_from_raw_altitudes() {
+        let network = chain("rerun");
+        burn(&network, BurnMethod::Breach);
+        let result = burn(&network, BurnMethod::Fill);
+        assert_burned(&result, &[12., 12., 9., 9.], &[10., 12., 8., 9.]);
+    }
+}
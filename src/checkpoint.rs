@@ -0,0 +1,167 @@
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+
+use rustc_hash::FxBuildHasher;
+use tokio::fs::{File, OpenOptions};
+use tokio::io::AsyncWriteExt;
+
+/// バッチの完了を表す行の接頭辞
+const COMMIT_PREFIX: &str = "#commit";
+
+/// 出力の形式を決めるオプションを記録する行の接頭辞
+const OPTIONS_PREFIX: &str = "#options ";
+
+/// collectの途中経過を記録するチェックポイントマニフェスト
+///
+/// 先頭行に出力の形式を決めるオプションを`#options ...`の形式で記録し、
+/// 処理済みタイルのパスを1行ずつ追記し、バッチが完了するたびに出力ファイルのバイト数を
+/// `#commit river_node.csv=1234 river_link.csv=567` の形式で追記する。
+/// 最後の`#commit`行より後に書かれたタイルは未完了として扱う。
+pub struct Checkpoint {
+    path: PathBuf,
+    file: File,
+}
+
+/// 読み込んだチェックポイントの内容
+pub struct CheckpointState {
+    /// 処理が完了したタイルのパス
+    pub completed: HashSet<String, FxBuildHasher>,
+    /// (ファイル名, 最後に整合していた時点のバイト数)
+    pub offsets: Vec<(String, u64)>,
+    /// チェックポイントを作成した時のオプション
+    pub options: Option<String>,
+}
+
+impl Checkpoint {
+    /// 新しいチェックポイントを作成し、`options`を記録する(既存の内容は破棄する)
+    /// `options`は改行を含まない文字列とする
+    pub async fn create(path: &Path, options: &str) -> Self {
+        let mut file = OpenOptions::new()
+            .create(true)
+            .write(true)
+            .truncate(true)
+            .open(path)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to create checkpoint file at {:?}: {:#?}", path, e));
+        file.write_all(format!("{OPTIONS_PREFIX}{options}\n").as_ref())
+            .await
+            .unwrap_or_else(|e| panic!("Failed to write checkpoint file at {:?}: {:#?}", path, e));
+
+        Self {
+            path: path.to_path_buf(),
+            file,
+        }
+    }
+
+    /// 既存のチェックポイントに追記する
+    pub async fn append(path: &Path) -> Self {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to open checkpoint file at {:?}: {:#?}", path, e));
+
+        Self {
+            path: path.to_path_buf(),
+            file,
+        }
+    }
+
+    /// チェックポイントを読み込む
+    /// ファイルが存在しない場合や、一度もバッチが完了していない場合は`None`を返す
+    pub fn load(path: &Path) -> Option<CheckpointState> {
+        let content = std::fs::read_to_string(path).ok()?;
+
+        let mut completed = HashSet::with_hasher(FxBuildHasher);
+        let mut pending = Vec::new();
+        let mut offsets = None;
+        let mut options = None;
+
+        // 改行で終わっていない行は書き込み途中で中断されたものなので無視する
+        for line in content.split_inclusive('\n').filter_map(|line| line.strip_suffix('\n')) {
+            if let Some(recorded) = line.strip_prefix(OPTIONS_PREFIX) {
+                options = Some(recorded.to_string());
+            } else if let Some(commit) = line.strip_prefix(COMMIT_PREFIX) {
+                let parsed = commit
+                    .split_whitespace()
+                    .map(|entry| {
+                        let (name, offset) = entry.split_once('=')?;
+                        Some((name.to_string(), offset.parse::<u64>().ok()?))
+                    })
+                    .collect::<Option<Vec<_>>>();
+
+                if let Some(parsed) = parsed {
+                    completed.extend(pending.drain(..));
+                    offsets = Some(parsed);
+                }
+            } else if !line.is_empty() {
+                pending.push(line.to_string());
+            }
+        }
+
+        Some(CheckpointState {
+            completed,
+            offsets: offsets?,
+            options,
+        })
+    }
+
+    /// 完了したバッチのタイルと、その時点での出力ファイルのバイト数を記録する
+    pub async fn commit(&mut self, tiles: &[String], outputs: &[&Path]) {
+        let mut buf = tiles.iter().map(|tile| format!("{tile}\n")).collect::<String>();
+
+        buf.push_str(COMMIT_PREFIX);
+        for output in outputs {
+            // 記録するバイト数がディスク上のデータを超えないよう、マニフェストより先に出力を同期する
+            tokio::fs::File::open(output)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to open {:?}: {:#?}", output, e))
+                .sync_data()
+                .await
+                .unwrap_or_else(|e| panic!("Failed to sync {:?}: {:#?}", output, e));
+            let len = tokio::fs::metadata(output)
+                .await
+                .unwrap_or_else(|e| panic!("Failed to read metadata of {:?}: {:#?}", output, e))
+                .len();
+            let name = output
+                .file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_else(|| panic!("Invalid output file name: {:?}", output));
+            buf.push_str(&format!(" {name}={len}"));
+        }
+        buf.push('\n');
+
+        self.file
+            .write_all(buf.as_ref())
+            .await
+            .unwrap_or_else(|e| panic!("Failed to write checkpoint file at {:?}: {:#?}", self.path, e));
+        self.file
+            .sync_data()
+            .await
+            .unwrap_or_else(|e| panic!("Failed to sync checkpoint file at {:?}: {:#?}", self.path, e));
+    }
+
+    /// 全ての処理が完了したのでチェックポイントを削除する
+    pub async fn remove(self) {
+        drop(self.file);
+        tokio::fs::remove_file(&self.path)
+            .await
+            .unwrap_or_else(|e| panic!("Failed to remove checkpoint file at {:?}: {:#?}", self.path, e));
+    }
+}
+
+impl CheckpointState {
+    /// 出力ファイルを最後に整合していた時点のバイト数まで切り詰める
+    pub fn truncate_outputs(&self, dir: &Path) {
+        for (name, offset) in &self.offsets {
+            let path = dir.join(name);
+            let file = std::fs::OpenOptions::new()
+                .write(true)
+                .open(&path)
+                .unwrap_or_else(|e| panic!("Failed to open {:?} for truncation: {:#?}", path, e));
+            file.set_len(*offset)
+                .unwrap_or_else(|e| panic!("Failed to truncate {:?}: {:#?}", path, e));
+        }
+    }
+}
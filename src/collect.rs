@@ -5,24 +5,67 @@ use std::sync::Arc;
 
 use anyhow::anyhow;
 use bitflags::{bitflags, Flags};
+use clap::ValueEnum;
 use coordinate_transformer::{ll2pixel, pixel2ll, ZoomLv};
-use csv::Reader;
 use futures::future;
-use geojson::{FeatureCollection, JsonObject, Value};
+use geojson::{FeatureCollection, JsonObject, JsonValue, Value};
 use hilbert_index::ToHilbertIndex;
-use image::ImageReader;
 use indicatif::{ProgressBar, ProgressStyle};
-use moka::future::Cache;
-use polars::prelude::{CsvWriter, SerWriter, UniqueKeepStrategy};
+use polars::prelude::{
+    col, len, lit, CsvWriter, DataType, Field, Schema, SerWriter, UniqueKeepStrategy,
+};
 use polars_lazy::prelude::{LazyCsvReader, LazyFileListReader};
 use rayon::prelude::*;
-use reqwest::Client;
-use rustc_hash::FxBuildHasher;
+use rustc_hash::FxHashMap;
 use tokio::fs::OpenOptions;
 use tokio::io::AsyncWriteExt;
 
+use crate::aoi::Aoi;
+use crate::checkpoint::Checkpoint;
+use crate::dem::{dem_url_template, DemSampler, DemSource, TileDem};
+use crate::distance::DistanceMeasure;
+use crate::fetch::TileFetcher;
+use crate::geotiff::GeoTiffDem;
+use crate::mokuroku::{download_mokuroku, read_mokuroku, write_mokuroku, TileEntry};
+use crate::stitch::stitch_tiles;
+use crate::update::{parse_link_key, read_rows, write_rows};
 use crate::CollectArgs;
 
+/// チェックポイントに記録する、出力の範囲・ID・列・値を決めるオプション
+/// `--resume`でこれらが異なると、同じファイルに異なる形式の行が追記されてしまう
+fn checkpoint_options(args: &CollectArgs) -> String {
+    let CollectArgs {
+        line,
+        category,
+        link_property,
+        dem_base_url,
+        dem_url,
+        dem_format,
+        zoom_lv,
+        dem_min_zoom,
+        dem_geotiff,
+        dem_source,
+        dem_interpolation,
+        distance,
+        id_zoom,
+        aabb,
+        aoi,
+        select,
+        ..
+    } = args;
+
+    format!(
+        "line={line:?} category={category:?} link_property={link_property:?} \
+         dem_base_url={dem_base_url:?} dem_url={dem_url:?} dem_format={dem_format:?} zoom_lv={zoom_lv} \
+         dem_min_zoom={dem_min_zoom} dem_geotiff={dem_geotiff:?} dem_source={dem_source:?} \
+         dem_interpolation={dem_interpolation:?} distance={:?} ellipsoid={:?} plane_zone={:?} \
+         id_zoom={id_zoom} aabb={aabb:?} aoi={aoi:?} select={select:?}",
+        distance.distance,
+        distance.ellipsoid,
+        distance.plane_zone,
+    )
+}
+
 /// collectサブコマンド用の関数
 pub async fn collect_river_data(args: &CollectArgs) {
     let spinner = ProgressBar::new_spinner();
@@ -32,45 +75,86 @@ pub async fn collect_river_data(args: &CollectArgs) {
     // デフォルト値の設定
     let CollectArgs {
         mokuroku,
+        fetch_mokuroku,
         batch: batch_size,
-        line,
-        category,
         river_base_url,
-        dem_base_url,
-        zoom_lv,
-        aabb,
+        resume,
+        stitch_tolerance,
+        deterministic,
+        ..
     } = args;
+    if *fetch_mokuroku {
+        spinner.set_message("Fetching mokuroku.csv.gz...");
+        download_mokuroku(river_base_url, Path::new(mokuroku)).await;
+    }
     let mokuroku = canonicalize(mokuroku).expect("Failed to canonicalize mokuroku file path");
-    let rv_ctg_flags = Arc::new(parse_flag_list::<RvCtgFlags>(category));
-    let rv_rcl_flags = Arc::new(parse_flag_list::<RvRclFlags>(line));
-    let river_base_url = Arc::new(river_base_url.clone());
-    let dem_base_url = Arc::new(dem_base_url.clone());
-    let dem_zoom_lv = ZoomLv::parse(*zoom_lv).expect("Failed to parse ZoomLv");
-    let aabb = aabb.clone().map(|s| s.parse::<AABB>().expect("Failed to parse AABB"));
 
-    spinner.set_message("Reading mokuroku.csv...");
-    let tiles = read_tile_list(&mokuroku, aabb);
+    let collector = Collector::new(args);
 
-    // 標高データのキャッシュ
-    let altitude_cache = Cache::<(u32, u32), Arc<Vec<f32>>>::builder()
-        .max_capacity(50)
-        .build_with_hasher(FxBuildHasher);
+    spinner.set_message("Reading mokuroku.csv...");
+    let entries = read_tile_list(&mokuroku, collector.region.as_deref());
+    let tiles = entries.iter().map(|entry| entry.path.clone()).collect::<Vec<_>>();
 
     let nodes_path = mokuroku.with_file_name("river_node.csv");
     let links_path = mokuroku.with_file_name("river_link.csv");
-
-    // ヘッダーの書き込み
-    {
-        spinner.set_message("Writing headers for nodes and links...");
-        write_nodes_header(&nodes_path).await;
-        write_link_header(&links_path).await;
-        spinner.finish_and_clear();
-    }
-
-    let client = Client::new();
+    let index_path = mokuroku.with_file_name("river_tile_index.csv");
+    let collision_path = mokuroku.with_file_name("river_node_collision.csv");
+    let checkpoint_path = mokuroku.with_file_name("collect_checkpoint.txt");
+    let output_dir = mokuroku.parent().expect("Failed to get the directory of mokuroku file");
+
+    let total_tiles = tiles.len();
+    let resumed = if *resume {
+        let state = Checkpoint::load(&checkpoint_path);
+        if state.is_none() {
+            eprintln!("No checkpoint found at {:?}. Starting from scratch.", checkpoint_path);
+        }
+        state
+    } else {
+        None
+    };
+
+    let (tiles, mut checkpoint) = match resumed {
+        // チェックポイントから再開する場合は、出力を最後に整合していた時点まで戻し、処理済みのタイルをスキップする
+        Some(state) => {
+            spinner.set_message("Restoring outputs from checkpoint...");
+            let options = checkpoint_options(args);
+            if state.options.as_deref() != Some(options.as_str()) {
+                panic!(
+                    "The checkpoint at {:?} was created with different options.\n  checkpoint: {}\n  current:    {}\nResume with the same options, or run without --resume to start over.",
+                    checkpoint_path,
+                    state.options.as_deref().unwrap_or("(not recorded)"),
+                    options
+                );
+            }
+            check_nodes_header(&nodes_path, collector.id_zoom);
+            check_link_header(&links_path, &collector.property_keys);
+            state.truncate_outputs(output_dir);
+            let tiles = tiles
+                .into_iter()
+                .filter(|tile| !state.completed.contains(tile))
+                .collect::<Vec<_>>();
+            spinner.finish_and_clear();
+
+            (tiles, Checkpoint::append(&checkpoint_path).await)
+        }
+        // ヘッダーの書き込み
+        None => {
+            spinner.set_message("Writing headers for nodes and links...");
+            write_nodes_header(&nodes_path, collector.id_zoom).await;
+            write_link_header(&links_path, &collector.property_keys).await;
+            write_tile_index_header(&index_path).await;
+
+            let mut checkpoint = Checkpoint::create(&checkpoint_path, &checkpoint_options(args)).await;
+            checkpoint.commit(&[], &[&nodes_path, &links_path, &index_path]).await;
+            spinner.finish_and_clear();
+
+            (tiles, checkpoint)
+        }
+    };
 
     // ProgressBarの設定
-    let pb = ProgressBar::new(tiles.len() as u64);
+    let pb = ProgressBar::new(total_tiles as u64);
+    pb.set_position((total_tiles - tiles.len()) as u64);
     pb.set_message("Starting to process river center line tiles...");
     pb.set_style(
         ProgressStyle::with_template("{msg}\n[{elapsed_precise}] {wide_bar} {pos}/{len} ({eta_precise})")
@@ -78,47 +162,252 @@ pub async fn collect_river_data(args: &CollectArgs) {
     );
 
     // バッチごとにタイルを処理
+    let mut failed_tiles = Vec::new();
     for (i, batch) in tiles.chunks(*batch_size).enumerate() {
-        let river_base_url = river_base_url.clone();
-        let lines = fetch_ml(river_base_url, batch, *rv_rcl_flags, *rv_ctg_flags, &client).await;
-
-        let links = collect_links(&lines);
-        let nodes = collect_nodes(
-            &lines,
-            dem_base_url.clone(),
-            dem_zoom_lv,
-            altitude_cache.clone(),
-            &client,
-        )
-            .await;
+        let CollectedBatch {
+            nodes,
+            links,
+            index,
+            failed,
+        } = collector.process(batch).await;
 
         write_nodes(&nodes_path, &nodes).await;
         write_links(&links_path, &links).await;
+        write_tile_index(&index_path, &index).await;
+
+        // バッチの完了を記録 (取得できなかったタイルは--resumeで取得し直すため、完了として記録しない)
+        let completed = batch
+            .iter()
+            .filter(|tile| !failed.contains(tile))
+            .cloned()
+            .collect::<Vec<_>>();
+        checkpoint.commit(&completed, &[&nodes_path, &links_path, &index_path]).await;
+        failed_tiles.extend(failed);
 
         pb.inc(batch.len() as u64);
 
         pb.set_message(format!(
             "Completed batch {} of {}",
             i + 1,
-            tiles.len() / batch_size,
+            tiles.len().div_ceil(*batch_size),
         ));
     }
 
     pb.finish_with_message("Finished processing all tiles!");
 
+    // 取得できなかったタイルがある場合は、後処理を行わずにチェックポイントを残す
+    if !failed_tiles.is_empty() {
+        eprintln!(
+            "Failed to fetch {} tiles. Run again with --resume to retry them: {:?}",
+            failed_tiles.len(),
+            failed_tiles
+        );
+        return;
+    }
+
+    // 以降の処理は出力ファイルを書き換えるため、チェックポイントは不要になる
+    checkpoint.remove().await;
+
     let spinner = ProgressBar::new_spinner();
     spinner.enable_steady_tick(std::time::Duration::from_millis(100));
 
     // ノード情報の重複削除
     spinner.set_message("Deduplicating nodes...");
-    deduplicate_nodes(&nodes_path);
+    let collisions = deduplicate_nodes(&nodes_path, &collision_path, *deterministic);
+    if collisions > 0 {
+        eprintln!(
+            "{} nodes with different coordinates or altitudes share an ID. See {:?}",
+            collisions, collision_path
+        );
+    }
+
+    // タイルの境界で分断された中心線の接続
+    if *stitch_tolerance > 0. {
+        spinner.set_message("Stitching lines across tile boundaries...");
+        stitch_tiles(
+            &nodes_path,
+            &links_path,
+            &index_path,
+            &mokuroku.with_file_name(STITCH_REPORT_FILE_NAME),
+            *stitch_tolerance,
+            &collector.distance,
+        );
+    }
+
+    // 実行ごとに同じ出力になるよう並べ替える
+    if *deterministic {
+        spinner.set_message("Sorting links...");
+        sort_links(&links_path, &index_path);
+    }
 
     // 日本の緯度経度のAABBから4点を追記する
     spinner.set_message("Appending bounds...");
-    append_bounds(nodes_path, aabb).await;
+    let bounds = collector.region.as_ref().map(|region| AABB::from(region.area.bounds()));
+    append_bounds(nodes_path, bounds, collector.id_zoom).await;
+
+    // 次回の差分更新のために、今回処理したタイルの一覧を保存する
+    spinner.set_message("Saving mokuroku snapshot...");
+    write_mokuroku(&mokuroku.with_file_name(SNAPSHOT_FILE_NAME), &entries);
     spinner.finish_with_message("Process completed!");
 }
 
+/// タイル境界の端点の接続結果を書き出すファイル名
+pub(crate) const STITCH_REPORT_FILE_NAME: &str = "river_stitch_report.csv";
+
+/// 前回処理したタイルの一覧を保存するファイル名
+pub(crate) const SNAPSHOT_FILE_NAME: &str = "river_mokuroku.csv";
+
+/// タイルの取得から標高の付与までを行うための共有状態
+pub(crate) struct Collector {
+    river_base_url: Arc<String>,
+    dem: DemSampler,
+    /// リンクの長さの求め方
+    pub distance: DistanceMeasure,
+    /// ノードIDに使うヒルベルト値のズームレベル
+    pub id_zoom: ZoomLv,
+    rv_rcl_flags: RvRclFlags,
+    rv_ctg_flags: RvCtgFlags,
+    /// リンクに書き出すフィーチャのプロパティのキー (`type`, `rivCtg`以外)
+    pub property_keys: Arc<Vec<String>>,
+    fetcher: TileFetcher,
+    /// 処理対象の範囲
+    pub region: Option<Arc<Region>>,
+}
+
+/// 1バッチ分の処理結果
+pub(crate) struct CollectedBatch {
+    pub nodes: Vec<RiverNode>,
+    pub links: Vec<Link>,
+    pub index: Vec<TileLink>,
+    /// 再試行しても取得できなかったタイルのパス
+    pub failed: Vec<String>,
+}
+
+impl Collector {
+    pub fn new(args: &CollectArgs) -> Self {
+        let CollectArgs {
+            line,
+            category,
+            link_property,
+            river_base_url,
+            dem_base_url,
+            dem_url,
+            dem_format,
+            zoom_lv,
+            dem_min_zoom,
+            dem_interpolation,
+            dem_geotiff,
+            dem_source,
+            distance,
+            id_zoom,
+            cache_dir,
+            aabb,
+            aoi,
+            select,
+            ..
+        } = args;
+
+        // --dem-sourceが無い場合は、GeoTIFF、DEMタイルの順とする
+        let dem_sources = if dem_source.is_empty() {
+            dem_geotiff
+                .as_deref()
+                .map(|path| DemSource::GeoTiff(GeoTiffDem::open(path)))
+                .into_iter()
+                .chain([DemSource::Tiles(TileDem::new(
+                    dem_url_template(dem_url, dem_base_url, *dem_format),
+                    *dem_format,
+                ))])
+                .collect()
+        } else {
+            dem_source.iter().map(|spec| DemSource::parse(spec)).collect()
+        };
+
+        let fetcher = TileFetcher::new(cache_dir.clone().map(PathBuf::from));
+        let dem = DemSampler::new(
+            dem_sources,
+            ZoomLv::parse(*zoom_lv).expect("Failed to parse ZoomLv"),
+            ZoomLv::parse(*dem_min_zoom).expect("Failed to parse ZoomLv"),
+            *dem_interpolation,
+            fetcher.clone(),
+        );
+
+        Self {
+            river_base_url: Arc::new(river_base_url.clone()),
+            dem,
+            distance: DistanceMeasure::new(distance),
+            id_zoom: ZoomLv::parse(*id_zoom).expect("Failed to parse ZoomLv"),
+            rv_rcl_flags: parse_flag_list::<RvRclFlags>(line),
+            rv_ctg_flags: parse_flag_list::<RvCtgFlags>(category),
+            property_keys: Arc::new(
+                link_property
+                    .split(',')
+                    .filter(|key| !key.is_empty())
+                    .map(str::to_string)
+                    .collect(),
+            ),
+            fetcher,
+            region: Region::from_args(aabb, aoi, *select).map(Arc::new),
+        }
+    }
+
+    /// タイルを取得し、ノードとリンクを収集する
+    /// 取得できなかったタイルは`CollectedBatch::failed`に返し、ノードとリンクには含めない
+    pub async fn process(&self, batch: &[String]) -> CollectedBatch {
+        let (tile_lines, failed) = fetch_ml(
+            self.river_base_url.clone(),
+            batch,
+            self.rv_rcl_flags,
+            self.rv_ctg_flags,
+            self.id_zoom,
+            self.property_keys.clone(),
+            &self.fetcher,
+        )
+            .await;
+
+        // 範囲外の部分を切り落とす
+        let tile_lines = match self.region.as_deref() {
+            Some(Region {
+                area,
+                mode: SelectionMode::Clip,
+            }) => tile_lines
+                .into_iter()
+                .map(|(tile, lines)| (tile, clip_lines(area, lines, self.id_zoom)))
+                .collect(),
+            _ => tile_lines,
+        };
+
+        // どのタイルから得られたリンクかを記録する
+        let index = tile_lines
+            .iter()
+            .flat_map(|(tile, lines)| {
+                lines.iter().flat_map(move |(line, _)| {
+                    line.windows(2)
+                        .map(move |link| (tile.clone(), link[0].0, link[1].0))
+                })
+            })
+            .collect::<Vec<_>>();
+
+        let lines = tile_lines
+            .into_iter()
+            .flat_map(|(_, lines)| lines)
+            .collect::<Vec<_>>();
+
+        let nodes = collect_nodes(&lines, &self.dem).await;
+        let altitudes = nodes
+            .iter()
+            .map(|(id, _, _, altitude)| (*id, altitude.map(|(altitude, _)| altitude)))
+            .collect::<FxHashMap<_, _>>();
+        let links = collect_links(&lines, &altitudes, &self.distance);
+
+        CollectedBatch {
+            nodes,
+            links,
+            index,
+            failed,
+        }
+    }
+}
+
 bitflags! {
     /// 河川中心線の種別
     #[derive(Copy, Clone)]
@@ -216,9 +505,82 @@ fn parse_flag_list<T: FromStr + Flags>(s: &str) -> T {
     list.fold(T::empty(), |acc, x| acc.union(x))
 }
 
+/// 範囲に対するタイルの選択方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum SelectionMode {
+    /// 範囲に完全に含まれるタイルのみを取得する
+    Contained,
+    /// 範囲と重なるタイルを全て取得する
+    Intersects,
+    /// 範囲と重なるタイルを取得し、中心線を範囲の境界で切断する
+    Clip,
+}
+
+/// 処理対象の範囲とタイルの選択方法
+pub(crate) struct Region {
+    pub area: Aoi,
+    pub mode: SelectionMode,
+}
+
+impl Region {
+    /// `--aabb`, `--aoi`, `--select`から作成する
+    /// 選択方法が指定されていない場合、AABBでは`contained`、ポリゴンでは`clip`とする
+    pub fn from_args(
+        aabb: &Option<String>,
+        aoi: &Option<String>,
+        select: Option<SelectionMode>,
+    ) -> Option<Self> {
+        if let Some(path) = aoi {
+            let area = Aoi::from_geojson_file(Path::new(path))
+                .unwrap_or_else(|e| panic!("Failed to load AOI: {:#?}", e));
+            Some(Self {
+                area,
+                mode: select.unwrap_or(SelectionMode::Clip),
+            })
+        } else {
+            let aabb = aabb.as_ref()?.parse::<AABB>().expect("Failed to parse AABB");
+            Some(Self {
+                area: aabb.to_aoi(),
+                mode: select.unwrap_or(SelectionMode::Contained),
+            })
+        }
+    }
+
+    /// タイルを取得対象とするか
+    fn selects(&self, tile: (ZoomLv, u32, u32)) -> bool {
+        let (min, max) = tile_bounds(tile);
+        match self.mode {
+            SelectionMode::Contained => self.area.contains_rect(min, max),
+            SelectionMode::Intersects | SelectionMode::Clip => self.area.intersects_rect(min, max),
+        }
+    }
+}
+
+/// 中心線をポリゴンの境界で切断し、範囲内の部分のみを残す
+/// 境界との交点は新しいノードになる
+fn clip_lines(aoi: &Aoi, lines: Vec<FetchedLine>, id_zoom: ZoomLv) -> Vec<FetchedLine> {
+    lines
+        .into_iter()
+        .flat_map(|(line, properties)| {
+            let coords = line.iter().map(|(_, long, lat)| (*long, *lat)).collect::<Vec<_>>();
+            aoi.clip_line(&coords)
+                .into_iter()
+                .map(move |piece| (piece, properties.clone()))
+        })
+        .map(|(piece, properties)| {
+            let line = piece
+                .into_iter()
+                .map(|(long, lat)| (calc_hilbert_index(long, lat, id_zoom), long, lat))
+                .collect();
+            (line, properties)
+        })
+        .collect()
+}
+
 /// タイルをフェッチする範囲を表す
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy)]
-struct AABB {
+pub(crate) struct AABB {
     min_long: f64,
     max_long: f64,
     min_lat: f64,
@@ -250,6 +612,31 @@ impl FromStr for AABB {
     }
 }
 
+impl AABB {
+    /// 矩形のポリゴンに変換
+    fn to_aoi(self) -> Aoi {
+        let ring = vec![
+            (self.min_long, self.min_lat),
+            (self.max_long, self.min_lat),
+            (self.max_long, self.max_lat),
+            (self.min_long, self.max_lat),
+        ];
+        Aoi::new(vec![vec![ring]])
+    }
+}
+
+impl From<(f64, f64, f64, f64)> for AABB {
+    /// (最小経度, 最大経度, 最小緯度, 最大緯度)から作成
+    fn from((min_long, max_long, min_lat, max_lat): (f64, f64, f64, f64)) -> Self {
+        Self {
+            min_long,
+            max_long,
+            min_lat,
+            max_lat,
+        }
+    }
+}
+
 impl Default for AABB {
     /// 日本の緯度経度のAABB
     fn default() -> Self {
@@ -262,52 +649,47 @@ impl Default for AABB {
     }
 }
 
-/// CSVファイルからタイルリストを読み込む
-/// タイルのURLの後半部分のみを格納したリストを返す
+/// mokurokuファイルからタイルリストを読み込む
+/// タイルのURLの後半部分と更新情報を格納したリストを返す
 /// 例: https://example.com/{z}/{x}/{y}.geojson -> {z}/{x}/{y}.geojson
-fn read_tile_list(path: &Path, aabb: Option<AABB>) -> Vec<String> {
-    let tile_list = Reader::from_path(path)
-        .unwrap_or_else(|_| panic!("Failed to read mokuroku CSV file at {:?}", path))
-        .into_records()
-        .filter_map(|record| {
-            let record = record.ok()?;
-            let url = record.get(0)?;
-            if url.chars().next()?.is_ascii_digit() {
-                Some(url.to_string())
-            } else {
-                None
-            }
-        });
-
-    if let Some(aabb) = aabb {
-        tile_list
-            .filter(|url| {
-                let url = url.as_str();
-                let mut zxy = url.split(".").next().unwrap().split('/');
+/// `region`が指定された場合は、その選択方法に従ってタイルを絞り込む
+pub(crate) fn read_tile_list(path: &Path, region: Option<&Region>) -> Vec<TileEntry> {
+    let tile_list = read_mokuroku(path).into_iter();
+
+    match region {
+        Some(region) => tile_list
+            .filter(|entry| region.selects(parse_tile_path(&entry.path)))
+            .collect(),
+        None => tile_list.collect(),
+    }
+}
 
-                let z = zxy.next().unwrap().parse::<ZoomLv>().unwrap();
-                let tile_x = zxy.next().unwrap().parse::<u32>().unwrap();
-                let tile_y = zxy.next().unwrap().parse::<u32>().unwrap();
+/// タイルのパスからズームレベルとタイル座標を読み取る
+/// 例: {z}/{x}/{y}.geojson -> (z, x, y)
+pub(crate) fn parse_tile_path(path: &str) -> (ZoomLv, u32, u32) {
+    let mut zxy = path.split(".").next().unwrap().split('/');
 
-                let (pixel_x_min, pixel_y_min) = (tile_x * 256, tile_y * 256);
-                let (pexel_x_max, pixel_y_max) = (pixel_x_min + 256, pixel_y_min + 256);
+    let z = zxy.next().unwrap().parse::<ZoomLv>().unwrap();
+    let tile_x = zxy.next().unwrap().parse::<u32>().unwrap();
+    let tile_y = zxy.next().unwrap().parse::<u32>().unwrap();
 
-                let (long_min, lat_min) = pixel2ll((pixel_x_min, pixel_y_min), z);
-                let (long_max, lat_max) = pixel2ll((pexel_x_max, pixel_y_max), z);
+    (z, tile_x, tile_y)
+}
 
-                let (long_min, long_max) = (long_min.to_degrees(), long_max.to_degrees());
-                let (lat_min, lat_max) = (lat_min.to_degrees(), lat_max.to_degrees());
+/// タイルが覆う範囲の(最小経度, 最小緯度)と(最大経度, 最大緯度) [度]
+pub(crate) fn tile_bounds((z, tile_x, tile_y): (ZoomLv, u32, u32)) -> ((f64, f64), (f64, f64)) {
+    // ピクセル座標のyは南向きに増えるため、タイルの左上が(最小経度, 最大緯度)になる
+    let (long_min, lat_max) = pixel2ll((tile_x * 256, tile_y * 256), z);
+    let (long_max, lat_min) = pixel2ll(((tile_x + 1) * 256, (tile_y + 1) * 256), z);
 
-                aabb.min_long <= long_min && long_max <= aabb.max_long && aabb.min_lat <= lat_min && lat_max <= aabb.max_lat
-            })
-            .collect()
-    } else {
-        tile_list.collect()
-    }
+    (
+        (long_min.to_degrees(), lat_min.to_degrees()),
+        (long_max.to_degrees(), lat_max.to_degrees()),
+    )
 }
 
 /// geojsonのプロパティからRvRclTypeとRivCtgを読み込む
-fn read_property(p: JsonObject) -> (RvRclFlags, RvCtgFlags) {
+fn read_property(p: &JsonObject) -> (RvRclFlags, RvCtgFlags) {
     let rv_rcl_type = p
         .get("type")
         .unwrap_or_else(|| panic!("Failed to get \"type\" property from JSON object: {:?}", p))
@@ -352,14 +734,14 @@ fn read_property(p: JsonObject) -> (RvRclFlags, RvCtgFlags) {
 }
 
 /// ヒルベルトインデックスを計算
-pub fn calc_hilbert_index(long: f64, lat: f64) -> usize {
-    let (x, y) = ll2pixel((long.to_radians(), lat.to_radians()), ZoomLv::Lv18);
-    let h = [x as usize, y as usize].to_hilbert_index(26);
-    h
+/// ズームレベル`zoom`のピクセル座標を次数`zoom + 8`のヒルベルト曲線に載せるため、ズームレベル24では64bitになる
+pub fn calc_hilbert_index(long: f64, lat: f64, zoom: ZoomLv) -> usize {
+    let (x, y) = ll2pixel((long.to_radians(), lat.to_radians()), zoom);
+    [x as usize, y as usize].to_hilbert_index(zoom as usize + 8)
 }
 
 /// 2地点間のハヴァーサイン距離を計算
-fn haversine_distance_m(long1: f64, lat1: f64, long2: f64, lat2: f64) -> f64 {
+pub(crate) fn haversine_distance_m(long1: f64, lat1: f64, long2: f64, lat2: f64) -> f64 {
     let d_long = long2 - long1;
     let d_lat = lat2 - lat1;
     let a = (d_lat / 2.).sin().powi(2);
@@ -368,31 +750,48 @@ fn haversine_distance_m(long1: f64, lat1: f64, long2: f64, lat2: f64) -> f64 {
     2. * r * (a + b).sqrt().asin() * 1000.
 }
 
-/// (ヒルベルト値, 経度, 緯度, 標高)
-type RiverNode = (usize, f64, f64, f32);
-
-/// Vec<(ヒルベルト値, 経度, 緯度)
-type FetchedLine = Vec<(usize, f64, f64)>;
+/// (ヒルベルト値, 経度, 緯度, (標高, 標高を求めたDEMの番号))
+pub(crate) type RiverNode = (usize, f64, f64, Option<(f32, usize)>);
+
+/// (Vec<(ヒルベルト値, 経度, 緯度)>, 中心線のプロパティ)
+type FetchedLine = (Vec<(usize, f64, f64)>, LineProperties);
+
+/// 中心線のプロパティの値 (種別、カテゴリ、`--link-property`で指定したキーの順)
+pub(crate) type LineProperties = Arc<Vec<String>>;
+
+/// フィーチャのプロパティからリンクに書き出す値を取り出す
+/// 文字列以外の値はJSONの表記のまま、存在しないキーは空文字列とする
+fn read_line_properties(p: &JsonObject, keys: &[String]) -> LineProperties {
+    let value = |key: &str| match p.get(key) {
+        Some(JsonValue::String(s)) => s.clone(),
+        Some(JsonValue::Null) | None => String::new(),
+        Some(v) => v.to_string(),
+    };
+
+    let values = ["type", "rivCtg"]
+        .into_iter()
+        .chain(keys.iter().map(String::as_str))
+        .map(value)
+        .collect();
+    Arc::new(values)
+}
 
 async fn fetch_single_ml(
     url: String,
     rv_rcl_flags: RvRclFlags,
     river_flags: RvCtgFlags,
-    client: &Client,
+    id_zoom: ZoomLv,
+    property_keys: Arc<Vec<String>>,
+    fetcher: &TileFetcher,
 ) -> anyhow::Result<Vec<FetchedLine>> {
-    let client = client.clone();
-
-    // HTTPリクエストの送信
-    let res = client.get(&url).send().await.map_err(|e| {
-        anyhow!(
-            "Failed to fetch tile data from URL: {}: {:#?}.",
-            url,
-            e
-        )
-    })?;
+    // タイルの取得
+    let bytes = fetcher
+        .fetch(&url)
+        .await?
+        .ok_or_else(|| anyhow!("Tile not found at URL: {}", url))?;
 
     // レスポンスボディの取得
-    let body = res.text().await.map_err(|e| {
+    let body = String::from_utf8(bytes).map_err(|e| {
         anyhow!(
             "Failed to parse response body as text from URL: {}: {:#?}",
             url,
@@ -431,7 +830,7 @@ async fn fetch_single_ml(
             )
         })?;
 
-        let (rv_rcl_type, riv_ctg) = read_property(properties);
+        let (rv_rcl_type, riv_ctg) = read_property(&properties);
 
         // フラグのチェック
         if !rv_rcl_flags.contains(rv_rcl_type) || !river_flags.contains(riv_ctg) {
@@ -460,7 +859,7 @@ async fn fetch_single_ml(
                         }
                         let long = p[0];
                         let lat = p[1];
-                        let h = calc_hilbert_index(long, lat);
+                        let h = calc_hilbert_index(long, lat, id_zoom);
                         Ok((h, long, lat))
                     })
                     .collect::<anyhow::Result<Vec<_>>>()?
@@ -474,7 +873,7 @@ async fn fetch_single_ml(
             }
         };
 
-        result.push(line);
+        result.push((line, read_line_properties(&properties, &property_keys)));
     }
 
     Ok(result)
@@ -484,21 +883,31 @@ async fn fetch_single_ml_with_retry(
     url: String,
     rv_rcl_flags: RvRclFlags,
     river_flags: RvCtgFlags,
-    client: &Client,
-) -> Vec<FetchedLine> {
+    id_zoom: ZoomLv,
+    property_keys: Arc<Vec<String>>,
+    fetcher: &TileFetcher,
+) -> Option<Vec<FetchedLine>> {
     const MAX_RETRY: usize = 5;
 
     let mut current_retry = 0;
     loop {
-        let result = fetch_single_ml(url.clone(), rv_rcl_flags, river_flags, client).await;
+        let result = fetch_single_ml(
+            url.clone(),
+            rv_rcl_flags,
+            river_flags,
+            id_zoom,
+            property_keys.clone(),
+            fetcher,
+        )
+            .await;
         match result {
-            Ok(result) => return result,
+            Ok(result) => return Some(result),
             Err(e) => {
                 eprintln!("Error: {:#?}", e);
                 current_retry += 1;
                 if current_retry >= MAX_RETRY {
                     eprintln!("Failed to fetch tile data from URL: {}", url);
-                    return Vec::new();
+                    return None;
                 }
             }
         }
@@ -506,47 +915,143 @@ async fn fetch_single_ml_with_retry(
 }
 
 /// 主線のフェッチとフィルタリング
+/// タイルのパスとそのタイルから得られた中心線の組と、取得できなかったタイルのパスを返す
 async fn fetch_ml(
     river_base_url: Arc<String>,
     url_part_list: &[String],
     rv_rcl_flags: RvRclFlags,
     river_flags: RvCtgFlags,
-    client: &Client,
-) -> Vec<FetchedLine> {
+    id_zoom: ZoomLv,
+    property_keys: Arc<Vec<String>>,
+    fetcher: &TileFetcher,
+) -> (Vec<(String, Vec<FetchedLine>)>, Vec<String>) {
     let futures = url_part_list
         .iter()
         .map(|url_part| {
             let url = format!("{river_base_url}{url_part}");
-            fetch_single_ml_with_retry(url, rv_rcl_flags, river_flags, client)
+            fetch_single_ml_with_retry(
+                url,
+                rv_rcl_flags,
+                river_flags,
+                id_zoom,
+                property_keys.clone(),
+                fetcher,
+            )
         })
         .collect::<Vec<_>>();
 
     let result = future::join_all(futures).await;
 
-    result.into_iter().flatten().collect()
+    let mut fetched = Vec::new();
+    let mut failed = Vec::new();
+    for (url_part, lines) in url_part_list.iter().cloned().zip(result) {
+        match lines {
+            Some(lines) => fetched.push((url_part, lines)),
+            None => failed.push(url_part),
+        }
+    }
+    (fetched, failed)
+}
+
+/// (StartID, EndID, リンクの形状, 中心線のプロパティ)
+pub(crate) type Link = (usize, usize, LinkGeometry, LineProperties);
+
+/// river_link.csvのリンクの形状の列名
+pub(crate) const LINK_GEOMETRY_COLUMNS: [&str; 5] = [
+    "length:float",
+    "drop:float",
+    "slope:float",
+    "length3d:float",
+    "bearing:float",
+];
+
+/// リンクの長さ・標高差・方位角
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct LinkGeometry {
+    /// 長さ[m]
+    pub length: f64,
+    /// 始点から終点への標高の低下量[m] (どちらかの標高が無い場合は`None`)
+    pub drop: Option<f64>,
+    /// 始点から終点への方位角[度] (北を0として時計回り)
+    pub bearing: f64,
+}
+
+impl LinkGeometry {
+    /// 始点と終点の(経度, 緯度, 標高)から求める (経度と緯度は度)
+    pub fn new(
+        (long1, lat1, altitude1): (f64, f64, Option<f32>),
+        (long2, lat2, altitude2): (f64, f64, Option<f32>),
+        length: f64,
+    ) -> Self {
+        let (long1, lat1, long2, lat2) = (
+            long1.to_radians(),
+            lat1.to_radians(),
+            long2.to_radians(),
+            lat2.to_radians(),
+        );
+        let d_long = long2 - long1;
+        let bearing = (d_long.sin() * lat2.cos())
+            .atan2(lat1.cos() * lat2.sin() - lat1.sin() * lat2.cos() * d_long.cos())
+            .to_degrees()
+            .rem_euclid(360.);
+
+        Self {
+            length,
+            drop: altitude1.zip(altitude2).map(|(a1, a2)| a1 as f64 - a2 as f64),
+            bearing,
+        }
+    }
+
+    /// `LINK_GEOMETRY_COLUMNS`の順の値 (標高差が無い場合、標高差・勾配・3次元の長さは空欄)
+    pub fn columns(&self) -> [String; 5] {
+        let format = |value: Option<f64>, precision: usize| {
+            value
+                .map(|value| format!("{:.*}", precision, value))
+                .unwrap_or_default()
+        };
+        // 長さ0のリンクの勾配は0とする
+        let slope = self
+            .drop
+            .map(|drop| if self.length > 0. { drop / self.length } else { 0. });
+        let length3d = self.drop.map(|drop| self.length.hypot(drop));
+
+        [
+            format(Some(self.length), 3),
+            format(self.drop, 3),
+            format(slope, 6),
+            format(length3d, 3),
+            format(Some(self.bearing), 2),
+        ]
+    }
 }
 
-/// (StartID, EndID, Distance)
-type Link = (usize, usize, f64);
+/// (タイルのパス, StartID, EndID)
+pub(crate) type TileLink = (String, usize, usize);
 
 /// フェッチした中心線情報から繋がりを収集
-fn collect_links(lines: &Vec<FetchedLine>) -> Vec<Link> {
+/// `altitudes`はノードIDごとの標高、`distance`はリンクの長さの求め方
+fn collect_links(
+    lines: &Vec<FetchedLine>,
+    altitudes: &FxHashMap<usize, Option<f32>>,
+    distance: &DistanceMeasure,
+) -> Vec<Link> {
     lines
         .into_par_iter()
-        .flat_map(|line| {
+        .flat_map(|(line, properties)| {
             line.windows(2)
                 .map(|link| {
                     let (id1, long1, lat1) = link[0];
                     let (id2, long2, lat2) = link[1];
 
-                    let dist = haversine_distance_m(
-                        long1.to_radians(),
-                        lat1.to_radians(),
-                        long2.to_radians(),
-                        lat2.to_radians(),
+                    let dist = distance.distance_m((long1, lat1), (long2, lat2));
+                    let altitude = |id: usize| altitudes.get(&id).copied().flatten();
+                    let geometry = LinkGeometry::new(
+                        (long1, lat1, altitude(id1)),
+                        (long2, lat2, altitude(id2)),
+                        dist,
                     );
 
-                    (id1, id2, dist)
+                    (id1, id2, geometry, properties.clone())
                 })
                 .collect::<Vec<_>>()
         })
@@ -554,82 +1059,12 @@ fn collect_links(lines: &Vec<FetchedLine>) -> Vec<Link> {
 }
 
 /// フェッチした中心線情報からノード情報を収集
-async fn collect_nodes(
-    lines: &Vec<FetchedLine>,
-    dem_base_url: Arc<String>,
-    dem_zoom_lv: ZoomLv,
-    cache: Cache<(u32, u32), Arc<Vec<f32>>, FxBuildHasher>,
-    client: &Client,
-) -> Vec<RiverNode> {
+async fn collect_nodes(lines: &[FetchedLine], dem: &DemSampler) -> Vec<RiverNode> {
     let futures = lines
-        .into_par_iter()
-        .flat_map(|line| {
-            line.into_par_iter().map(|n| async {
-                let (h, long, lat) = n;
-                let pixel_coord = ll2pixel((long.to_radians(), lat.to_radians()), dem_zoom_lv);
-                let tile_coord = (pixel_coord.0 / 256, pixel_coord.1 / 256);
-
-                let altitude_map = cache
-                    .entry(tile_coord)
-                    .or_insert_with(async {
-                        let z = dem_zoom_lv as u8;
-                        let (tile_x, tile_y) = tile_coord;
-                        // 産総研のシームレス標高タイルの仕様に合わせる
-                        let url = format!("{dem_base_url}{z}/{tile_y}/{tile_x}.png");
-
-                        let res = client.get(&url).send().await.unwrap_or_else(|e| {
-                            panic!("Failed to fetch DEM tile data from URL: {}: {:#?}", url, e)
-                        });
-
-                        let bytes = res.bytes().await.unwrap_or_else(|e| {
-                            panic!(
-                                "Failed to parse response body as bytes from URL: {}: {:#?}",
-                                url, e
-                            )
-                        });
-
-                        let altitudes = ImageReader::new(std::io::Cursor::new(bytes))
-                            .with_guessed_format()
-                            .unwrap_or_else(|e| {
-                                panic!(
-                                    "Failed to guess image format from bytes at URL: {}: {:#?}",
-                                    url, e
-                                )
-                            })
-                            .decode()
-                            .map(|image| {
-                                image
-                                    .into_rgb8()
-                                    .pixels()
-                                    .map(|color| {
-                                        let r = color[0] as f64;
-                                        let g = color[1] as f64;
-                                        let b = color[2] as f64;
-
-                                        let x = 2_f64.powi(16) * r + 2_f64.powi(8) * g + b;
-                                        let u = 0.01;
-
-                                        (if x < 2_f64.powi(23) {
-                                            x * u
-                                        } else if x > 2_f64.powi(23) {
-                                            (x - 2_f64.powi(24)) * u
-                                        } else {
-                                            0.
-                                        }) as f32
-                                    })
-                                    .collect::<Vec<_>>()
-                            })
-                            .unwrap_or_else(|_| vec![0.; 256 * 256]);
-
-                        Arc::new(altitudes)
-                    })
-                    .await;
-                let altitude_map = altitude_map.value();
-
-                let (local_x, local_y) = (pixel_coord.0 % 256, pixel_coord.1 % 256);
-                let altitude = altitude_map[(local_y * 256 + local_x) as usize];
-
-                let node: RiverNode = (*h, *long, *lat, altitude);
+        .iter()
+        .flat_map(|(line, _)| {
+            line.iter().map(|&(h, long, lat)| async move {
+                let node: RiverNode = (h, long, lat, dem.sample(long, lat).await);
                 node
             })
         })
@@ -639,7 +1074,7 @@ async fn collect_nodes(
 }
 
 /// ヘッダーの書き込み
-async fn write_nodes_header(path: &Path) {
+async fn write_nodes_header(path: &Path, id_zoom: ZoomLv) {
     let mut file = OpenOptions::new()
         .create(true)
         .write(true)
@@ -648,14 +1083,7 @@ async fn write_nodes_header(path: &Path) {
         .await
         .expect("Failed to create river_node.csv");
 
-    let header = [
-        "hilbert18:ID",
-        "location:point{crs:WGS-84}",
-        "altitude:float",
-        ":LABEL",
-    ]
-        .join(",")
-        + "\n";
+    let header = nodes_header(id_zoom) + "\n";
 
     file.write_all(header.as_ref())
         .await
@@ -663,6 +1091,18 @@ async fn write_nodes_header(path: &Path) {
     file.flush().await.expect("Failed to flush river_node.csv");
 }
 
+/// river_node.csvのヘッダー
+fn nodes_header(id_zoom: ZoomLv) -> String {
+    [
+        node_id_column(id_zoom).as_str(),
+        LOCATION_COLUMN,
+        ALTITUDE_COLUMN,
+        ALTITUDE_SOURCE_COLUMN,
+        ":LABEL",
+    ]
+        .join(",")
+}
+
 /// ノード情報の書き込み
 async fn write_nodes(path: &Path, lines: &[RiverNode]) {
     let mut file = OpenOptions::new()
@@ -672,21 +1112,7 @@ async fn write_nodes(path: &Path, lines: &[RiverNode]) {
         .await
         .expect("Failed to create river_node.csv");
 
-    let buf = lines
-        .iter()
-        .map(|(id, long, lat, altitude)| {
-            let location = format!("\"{{longitude:{long},latitude:{lat}}}\"");
-            [
-                id.to_string(),
-                location,
-                altitude.to_string(),
-                "RiverNode".to_string(),
-            ]
-                .join(",")
-                + "\n"
-        })
-        .collect::<Vec<_>>()
-        .concat();
+    let buf = lines.iter().map(node_row).collect::<Vec<_>>().concat();
 
     file.write_all(buf.as_ref())
         .await
@@ -694,8 +1120,48 @@ async fn write_nodes(path: &Path, lines: &[RiverNode]) {
     file.flush().await.expect("Failed to flush river_node.csv");
 }
 
+/// ノード情報を1行のCSVに変換
+pub(crate) fn node_row((id, long, lat, altitude): &RiverNode) -> String {
+    let location = format!("\"{{longitude:{long},latitude:{lat}}}\"");
+    [
+        id.to_string(),
+        location,
+        // 標高が得られなかったノードは空欄にする
+        altitude.map(|(altitude, _)| altitude.to_string()).unwrap_or_default(),
+        altitude.map(|(_, source)| source.to_string()).unwrap_or_default(),
+        "RiverNode".to_string(),
+    ]
+        .join(",")
+        + "\n"
+}
+
+/// river_link.csvのヘッダー
+/// プロパティの列は`--link-property`で指定したキーごとに`{key}:string`となる
+fn link_header(property_keys: &[String]) -> String {
+    [":START_ID", ":END_ID", ":TYPE"]
+        .into_iter()
+        .chain(LINK_GEOMETRY_COLUMNS)
+        .chain(["rvrcl_type:string", "riv_ctg:string"])
+        .map(str::to_string)
+        .chain(property_keys.iter().map(|key| format!("{key}:string")))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// 既存のriver_link.csvのヘッダーが`--link-property`と一致するかを確認する
+pub(crate) fn check_link_header(links_path: &Path, property_keys: &[String]) {
+    let expected = link_header(property_keys);
+    let actual = read_rows(links_path).0;
+    if actual != expected {
+        panic!(
+            "{:?} has columns {:?}, but the current options expect {:?}. Use the same --link-property as the previous run, on files not yet rewritten by `network` subcommands.",
+            links_path, actual, expected
+        );
+    }
+}
+
 /// ヘッダーの書き込み
-async fn write_link_header(path: &Path) {
+async fn write_link_header(path: &Path, property_keys: &[String]) {
     let mut file = OpenOptions::new()
         .create(true)
         .write(true)
@@ -704,7 +1170,7 @@ async fn write_link_header(path: &Path) {
         .await
         .expect("Failed to create river_link.csv");
 
-    let header = [":START_ID", ":END_ID", ":TYPE", "length:float"].join(",") + "\n";
+    let header = link_header(property_keys) + "\n";
 
     file.write_all(header.as_ref())
         .await
@@ -724,39 +1190,170 @@ async fn write_links(path: &Path, lines: &[Link]) {
         .await
         .expect("Failed to create river_link.csv");
 
-    let buf = lines
+    let buf = lines.iter().map(link_row).collect::<Vec<_>>().concat();
+
+    file.write_all(buf.as_ref())
+        .await
+        .expect("Failed to write river_link.csv");
+    file.flush()
+        .await
+        .expect("Failed to flush river_link.csv");
+}
+
+/// リレーション情報を1行のCSVに変換
+pub(crate) fn link_row((id1, id2, geometry, properties): &Link) -> String {
+    [id1.to_string(), id2.to_string(), "RIVER_LINK".to_string()]
+        .into_iter()
+        .chain(geometry.columns())
+        .chain(properties.iter().map(|value| escape_csv(value)))
+        .collect::<Vec<_>>()
+        .join(",")
+        + "\n"
+}
+
+/// コンマや引用符を含む値を引用符で囲む
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+/// ヘッダーの書き込み
+async fn write_tile_index_header(path: &Path) {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .await
+        .expect("Failed to create river_tile_index.csv");
+
+    file.write_all(TILE_INDEX_HEADER.as_ref())
+        .await
+        .expect("Failed to write header to river_tile_index.csv");
+    file.flush()
+        .await
+        .expect("Failed to flush river_tile_index.csv");
+}
+
+/// river_tile_index.csvのヘッダー
+pub(crate) const TILE_INDEX_HEADER: &str = "tile,start_id,end_id\n";
+
+/// タイルとリンクの対応の書き込み
+async fn write_tile_index(path: &Path, index: &[TileLink]) {
+    let mut file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await
+        .expect("Failed to create river_tile_index.csv");
+
+    let buf = index
         .iter()
-        .map(|(id1, id2, dist)| {
-            [
-                id1.to_string(),
-                id2.to_string(),
-                "RIVER_LINK".to_string(),
-                format!("{:.3}", dist),
-            ]
-                .join(",")
-                + "\n"
-        })
+        .map(|(tile, id1, id2)| format!("{tile},{id1},{id2}\n"))
         .collect::<Vec<_>>()
         .concat();
 
     file.write_all(buf.as_ref())
         .await
-        .expect("Failed to write river_link.csv");
+        .expect("Failed to write river_tile_index.csv");
     file.flush()
         .await
-        .expect("Failed to flush river_link.csv");
+        .expect("Failed to flush river_tile_index.csv");
 }
 
+/// ノードIDの列名 ex) hilbert18:ID
+pub(crate) fn node_id_column(id_zoom: ZoomLv) -> String {
+    format!("hilbert{}:ID", id_zoom as u8)
+}
+
+/// 既存のriver_node.csvのID列が`--id-zoom`と一致し、列が現在のバージョンと同じかを確認する
+pub(crate) fn check_nodes_header(nodes_path: &Path, id_zoom: ZoomLv) {
+    let expected = node_id_column(id_zoom);
+    let actual = read_node_id_column(nodes_path);
+    if actual != expected {
+        panic!(
+            "{:?} uses {:?} as node ID, but --id-zoom {} expects {:?}",
+            nodes_path, actual, id_zoom as u8, expected
+        );
+    }
+
+    let expected = nodes_header(id_zoom);
+    let actual = read_rows(nodes_path).0;
+    if actual != expected {
+        panic!(
+            "{:?} has columns {:?}, but {:?} is expected. Run collect from scratch, or use files not yet rewritten by `network` subcommands.",
+            nodes_path, actual, expected
+        );
+    }
+}
+
+/// river_node.csvのヘッダーからID列の名前を読み取る
+fn read_node_id_column(nodes_path: &Path) -> String {
+    let file = std::fs::File::open(nodes_path)
+        .unwrap_or_else(|e| panic!("Failed to open {:?}: {:#?}", nodes_path, e));
+    let mut header = String::new();
+    std::io::BufRead::read_line(&mut std::io::BufReader::new(file), &mut header)
+        .unwrap_or_else(|e| panic!("Failed to read {:?}: {:#?}", nodes_path, e));
+
+    header.split(',').next().unwrap_or_default().trim_end().to_string()
+}
+
+/// river_node.csvの位置の列名
+const LOCATION_COLUMN: &str = "location:point{crs:WGS-84}";
+
+/// river_node.csvの標高の列名
+const ALTITUDE_COLUMN: &str = "altitude:float";
+
+/// river_node.csvの標高を求めたDEMの番号の列名
+const ALTITUDE_SOURCE_COLUMN: &str = "altitude_source:int";
+
 /// ノード情報の重複削除
-fn deduplicate_nodes(nodes_path: &Path) {
-    let mut df_deduplicated = LazyCsvReader::new(nodes_path)
+/// 同じIDに異なる座標・標高のノードが含まれていた場合は、それらを`collision_path`に書き出す
+/// `deterministic`が指定された場合は、ID・座標・標高の順に並べて各IDの先頭の行を残し、ID順に書き出す
+/// 戻り値は衝突したノードの数
+fn deduplicate_nodes(nodes_path: &Path, collision_path: &Path, deterministic: bool) -> usize {
+    let id_column = read_node_id_column(nodes_path);
+
+    // 64bitのヒルベルト値はi64に収まらないため、ID列はu64として読み込む
+    let schema = Schema::from_iter([Field::new(&id_column, DataType::UInt64)]);
+    let nodes = LazyCsvReader::new(nodes_path)
         .with_has_header(true)
+        .with_dtype_overwrite(Some(Arc::new(schema)))
         .finish()
         .expect("Failed to read river_node.csv")
-        .unique(
-            Some(vec!["hilbert18:ID".to_string()]),
-            UniqueKeepStrategy::Any,
-        )
+        // 座標と標高まで同じノードは単純な重複として扱う
+        .unique(None, UniqueKeepStrategy::Any);
+    let sort_columns = [id_column.as_str(), LOCATION_COLUMN, ALTITUDE_COLUMN];
+
+    let mut df_collision = nodes
+        .clone()
+        .filter(len().over([col(&id_column)]).gt(lit(1)))
+        .sort(sort_columns, Default::default())
+        .collect()
+        .expect("Failed to detect node ID collisions");
+    let collisions = df_collision.height();
+
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(collision_path)
+        .expect("Failed to create river_node_collision.csv");
+    CsvWriter::new(std::io::BufWriter::new(file))
+        .finish(&mut df_collision)
+        .expect("Failed to write river_node_collision.csv");
+
+    let nodes = if deterministic {
+        nodes
+            .sort(sort_columns, Default::default())
+            .unique_stable(Some(vec![id_column.clone()]), UniqueKeepStrategy::First)
+    } else {
+        nodes.unique(Some(vec![id_column.clone()]), UniqueKeepStrategy::Any)
+    };
+    let mut df_deduplicated = nodes
         .collect()
         .expect("Failed to deduplicate river_node.csv");
 
@@ -771,10 +1368,27 @@ fn deduplicate_nodes(nodes_path: &Path) {
     CsvWriter::new(buf)
         .finish(&mut df_deduplicated)
         .expect("Failed to write river_node.csv");
+
+    collisions
+}
+
+/// リンクを(始点, 終点)の順に、タイルとリンクの対応を(z, x, y, 始点, 終点)の順に並べ替える
+pub(crate) fn sort_links(links_path: &Path, index_path: &Path) {
+    let (header, mut rows) = read_rows(links_path);
+    rows.sort_by_cached_key(|row| (parse_link_key(row.split(',')), row.clone()));
+    write_rows(links_path, &header, &rows);
+
+    let (header, mut rows) = read_rows(index_path);
+    rows.sort_by_cached_key(|row| {
+        let mut iter = row.split(',');
+        let (z, x, y) = parse_tile_path(iter.next().unwrap_or_default());
+        ((z as u8, x, y), parse_link_key(iter))
+    });
+    write_rows(index_path, &header, &rows);
 }
 
 // AABBから4点を追記する
-async fn append_bounds(path: PathBuf, aabb: Option<AABB>) {
+async fn append_bounds(path: PathBuf, aabb: Option<AABB>, id_zoom: ZoomLv) {
     let AABB {
         min_long,
         max_long,
@@ -790,10 +1404,10 @@ async fn append_bounds(path: PathBuf, aabb: Option<AABB>) {
         .expect("Failed to create river_node.csv");
     let buf =
         [
-            (calc_hilbert_index(max_long, max_lat), min_long, min_lat, 0.),
-            (calc_hilbert_index(min_long, max_lat), max_long, min_lat, 0.),
-            (calc_hilbert_index(max_long, min_lat), min_long, max_lat, 0.),
-            (calc_hilbert_index(min_long, min_lat), max_long, max_lat, 0.),
+            (calc_hilbert_index(max_long, max_lat, id_zoom), min_long, min_lat, 0.),
+            (calc_hilbert_index(min_long, max_lat, id_zoom), max_long, min_lat, 0.),
+            (calc_hilbert_index(max_long, min_lat, id_zoom), min_long, max_lat, 0.),
+            (calc_hilbert_index(min_long, min_lat, id_zoom), max_long, max_lat, 0.),
         ]
             .iter()
             .map(|(id, long, lat, altitude)| {
@@ -802,6 +1416,7 @@ async fn append_bounds(path: PathBuf, aabb: Option<AABB>) {
                     id.to_string(),
                     location,
                     altitude.to_string(),
+                    String::new(),
                     "BoundNode".to_string(),
                 ]
                     .join(",")
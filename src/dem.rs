@@ -0,0 +1,425 @@
+use std::collections::hash_map::Entry;
+use std::f64::consts::PI;
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use clap::ValueEnum;
+use coordinate_transformer::ZoomLv;
+use image::ImageReader;
+use moka::future::Cache;
+use rustc_hash::{FxBuildHasher, FxHashMap};
+
+use crate::fetch::TileFetcher;
+use crate::geotiff::GeoTiffDem;
+
+/// DEMタイルの画素の補間方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Interpolation {
+    /// 最も近い画素の値
+    Nearest,
+    /// 周囲4画素の双線形補間
+    Bilinear,
+    /// 周囲16画素の双三次補間 (欠損値を含む場合は双線形補間)
+    Bicubic,
+}
+
+/// DEMタイルの形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DemFormat {
+    /// 産総研のシームレス標高タイル・国土地理院の標高タイル(PNG)
+    Gsj,
+    /// Mapbox Terrain-RGB
+    Mapbox,
+    /// Mapzen Terrarium
+    Terrarium,
+    /// 国土地理院の標高タイル(テキスト)
+    GsiText,
+}
+
+impl DemFormat {
+    /// `--dem-url`を省略した場合に`--dem-base-url`に続けるタイルのパス
+    fn default_layout(self) -> &'static str {
+        match self {
+            DemFormat::Gsj => "{z}/{y}/{x}.png",
+            DemFormat::Mapbox | DemFormat::Terrarium => "{z}/{x}/{y}.png",
+            DemFormat::GsiText => "{z}/{x}/{y}.txt",
+        }
+    }
+
+    /// タイルのデータを標高の配列に変換する (欠損値は`NaN`)
+    fn decode(self, bytes: &[u8]) -> anyhow::Result<Vec<f32>> {
+        let altitudes = match self {
+            DemFormat::Gsj => decode_rgb(bytes, |r, g, b| {
+                let x = 2_f64.powi(16) * r + 2_f64.powi(8) * g + b;
+                let u = 0.01;
+
+                if x < 2_f64.powi(23) {
+                    x * u
+                } else if x > 2_f64.powi(23) {
+                    (x - 2_f64.powi(24)) * u
+                } else {
+                    f64::NAN
+                }
+            })?,
+            DemFormat::Mapbox => decode_rgb(bytes, |r, g, b| {
+                -10000. + (r * 256. * 256. + g * 256. + b) * 0.1
+            })?,
+            DemFormat::Terrarium => decode_rgb(bytes, |r, g, b| r * 256. + g + b / 256. - 32768.)?,
+            DemFormat::GsiText => decode_gsi_text(bytes)?,
+        };
+
+        if altitudes.len() != (TILE_SIZE * TILE_SIZE) as usize {
+            return Err(anyhow!(
+                "DEM tile must have {} x {} pixels, but has {}",
+                TILE_SIZE,
+                TILE_SIZE,
+                altitudes.len()
+            ));
+        }
+        Ok(altitudes)
+    }
+}
+
+/// DEMタイルのURLのテンプレート
+/// `--dem-url`が指定されていなければ、`--dem-base-url`に形式ごとの既定のパスを続ける
+pub(crate) fn dem_url_template(dem_url: &Option<String>, dem_base_url: &str, format: DemFormat) -> String {
+    match dem_url {
+        Some(dem_url) => dem_url.clone(),
+        None => format!("{dem_base_url}{}", format.default_layout()),
+    }
+}
+
+/// テンプレートの`{z}`, `{x}`, `{y}`をタイル座標に置き換える
+pub(crate) fn tile_url(template: &str, z: u8, x: u32, y: u32) -> String {
+    template
+        .replace("{z}", &z.to_string())
+        .replace("{x}", &x.to_string())
+        .replace("{y}", &y.to_string())
+}
+
+/// テンプレートのうち、タイル座標を含むパスの部分 (クエリ文字列は除く)
+/// ex) https://example.com/dem/{z}/{x}/{y}.png?key=abc -> {z}/{x}/{y}.png
+pub(crate) fn tile_layout(template: &str) -> &str {
+    let path = template.split('?').next().unwrap_or_default();
+    let first = ["{z}", "{x}", "{y}"]
+        .iter()
+        .filter_map(|placeholder| path.find(placeholder))
+        .min()
+        .unwrap_or_else(|| panic!("DEM URL template must contain {{z}}, {{x}} and {{y}}: {}", template));
+
+    match path[..first].rfind('/') {
+        Some(slash) => &path[slash + 1..],
+        None => path,
+    }
+}
+
+/// 標高タイルの1辺の画素数
+const TILE_SIZE: i64 = 256;
+
+/// 欠損値を`NaN`で表した標高タイル
+type DemTile = Arc<Vec<f32>>;
+
+/// 標高を求めるデータ
+pub(crate) enum DemSource {
+    Tiles(TileDem),
+    GeoTiff(GeoTiffDem),
+}
+
+impl DemSource {
+    /// `--dem-source`の`FORMAT=LOCATION`を読み込む
+    /// FORMATが`geotiff`の場合はLOCATIONをGeoTIFFのディレクトリかVRTファイル、それ以外はタイルのURLのテンプレートとする
+    /// ex) gsj=https://tiles.gsj.jp/tiles/elev/land/{z}/{y}/{x}.png, geotiff=./lidar/
+    pub fn parse(spec: &str) -> Self {
+        match split_dem_source(spec) {
+            (None, location) => DemSource::GeoTiff(GeoTiffDem::open(location)),
+            (Some(format), url_template) => DemSource::Tiles(TileDem::new(url_template.to_string(), format)),
+        }
+    }
+}
+
+/// `--dem-source`の`FORMAT=LOCATION`を(タイルの形式, LOCATION)に分ける
+/// FORMATが`geotiff`の場合はタイルの形式を`None`とし、それ以外はLOCATIONが`{z}`, `{x}`, `{y}`を含むかを確認する
+pub(crate) fn split_dem_source(spec: &str) -> (Option<DemFormat>, &str) {
+    let (format, location) = spec
+        .split_once('=')
+        .unwrap_or_else(|| panic!("--dem-source must be FORMAT=LOCATION: {}", spec));
+
+    if format.eq_ignore_ascii_case("geotiff") {
+        return (None, location);
+    }
+    let format = DemFormat::from_str(format, true).unwrap_or_else(|_| {
+        panic!(
+            "Unknown DEM format {:?} in --dem-source. Use gsj, mapbox, terrarium, gsi-text or geotiff",
+            format
+        )
+    });
+    tile_layout(location);
+    (Some(format), location)
+}
+
+/// DEMタイル
+pub(crate) struct TileDem {
+    /// `{z}`, `{x}`, `{y}`を含むタイルのURL
+    url_template: String,
+    format: DemFormat,
+    /// (z, x, y)ごとのタイル (タイルが存在しない場合は`None`)
+    cache: Cache<(u8, u32, u32), Option<DemTile>, FxBuildHasher>,
+}
+
+impl TileDem {
+    pub fn new(url_template: String, format: DemFormat) -> Self {
+        // 補間でタイルの境界をまたぐため、少し多めにキャッシュする
+        let cache = Cache::builder()
+            .max_capacity(200)
+            .build_with_hasher(FxBuildHasher);
+
+        Self {
+            url_template,
+            format,
+            cache,
+        }
+    }
+
+    /// 地点の標高[m]
+    /// 指定したズームレベルのタイルが存在しない場合は、`min_zoom`まで順に低いズームレベルのタイルを使う
+    async fn sample(&self, long: f64, lat: f64, sampler: &DemSampler) -> Option<f32> {
+        for z in (sampler.min_zoom as u8..=sampler.zoom as u8).rev() {
+            let (x, y) = pixel_position(long, lat, z);
+            let (x0, y0) = (x.floor() as i64, y.floor() as i64);
+            let (center, _) = locate(z, x0, y0)?;
+            if self.tile(z, center.0, center.1, &sampler.fetcher).await.is_none() {
+                continue;
+            }
+
+            // 補間で参照する周囲2画素までを含むタイルを先に取得しておく
+            let mut tiles = FxHashMap::default();
+            for (dx, dy) in [(-2, -2), (2, -2), (-2, 2), (2, 2)] {
+                if let Some((key, _)) = locate(z, x0 + dx, y0 + dy) {
+                    if let Entry::Vacant(entry) = tiles.entry(key) {
+                        entry.insert(self.tile(z, key.0, key.1, &sampler.fetcher).await);
+                    }
+                }
+            }
+
+            return interpolate(sampler.interpolation, x, y, |x, y| {
+                let (key, index) = locate(z, x, y)?;
+                let value = tiles.get(&key)?.as_ref()?[index];
+                (!value.is_nan()).then_some(value)
+            });
+        }
+
+        None
+    }
+
+    /// タイルを取得する
+    async fn tile(&self, z: u8, x: u32, y: u32, fetcher: &TileFetcher) -> Option<DemTile> {
+        self.cache
+            .get_with((z, x, y), async {
+                let url = tile_url(&self.url_template, z, x, y);
+
+                // 取得できなかったタイルは、壊れたタイルと同じく低いズームレベルで補う
+                const MAX_RETRY: usize = 5;
+                let mut current_retry = 0;
+                let bytes = loop {
+                    match fetcher.fetch(&url).await {
+                        Ok(bytes) => break bytes?,
+                        Err(e) => {
+                            eprintln!("Error: {:#?}", e);
+                            current_retry += 1;
+                            if current_retry >= MAX_RETRY {
+                                eprintln!("Failed to fetch DEM tile data from URL: {}", url);
+                                return None;
+                            }
+                        }
+                    }
+                };
+
+                match self.format.decode(&bytes) {
+                    Ok(altitudes) => Some(Arc::new(altitudes)),
+                    Err(e) => {
+                        // 壊れたタイルは存在しないものとして、低いズームレベルで補う
+                        eprintln!("Failed to decode DEM tile at URL: {}: {:#?}", url, e);
+                        None
+                    }
+                }
+            })
+            .await
+    }
+}
+
+/// 優先順に並べたDEMから任意の地点の標高を求める
+#[derive(Clone)]
+pub(crate) struct DemSampler {
+    sources: Arc<Vec<DemSource>>,
+    zoom: ZoomLv,
+    min_zoom: ZoomLv,
+    interpolation: Interpolation,
+    fetcher: TileFetcher,
+}
+
+impl DemSampler {
+    pub fn new(
+        sources: Vec<DemSource>,
+        zoom: ZoomLv,
+        min_zoom: ZoomLv,
+        interpolation: Interpolation,
+        fetcher: TileFetcher,
+    ) -> Self {
+        if min_zoom as u8 > zoom as u8 {
+            panic!(
+                "--dem-min-zoom ({}) must not be greater than --zoom-lv ({})",
+                min_zoom as u8, zoom as u8
+            );
+        }
+
+        Self {
+            sources: Arc::new(sources),
+            zoom,
+            min_zoom,
+            interpolation,
+            fetcher,
+        }
+    }
+
+    /// 地点の標高[m]と、標高を求めたDEMの番号
+    /// 先頭のDEMから順に、欠損値でない標高が得られたものを使う (どのDEMからも得られない場合は`None`)
+    pub async fn sample(&self, long: f64, lat: f64) -> Option<(f32, usize)> {
+        for (index, source) in self.sources.iter().enumerate() {
+            let altitude = match source {
+                DemSource::Tiles(tiles) => tiles.sample(long, lat, self).await,
+                DemSource::GeoTiff(geotiff) => geotiff.sample(long, lat, self.interpolation).await,
+            };
+            if let Some(altitude) = altitude {
+                return Some((altitude, index));
+            }
+        }
+
+        None
+    }
+}
+
+/// RGBの画像を`altitude(r, g, b)`で標高の配列に変換する
+fn decode_rgb(bytes: &[u8], altitude: impl Fn(f64, f64, f64) -> f64) -> anyhow::Result<Vec<f32>> {
+    let image = ImageReader::new(std::io::Cursor::new(bytes))
+        .with_guessed_format()?
+        .decode()?
+        .into_rgb8();
+
+    let altitudes = image
+        .pixels()
+        .map(|color| altitude(color[0] as f64, color[1] as f64, color[2] as f64) as f32)
+        .collect();
+
+    Ok(altitudes)
+}
+
+/// 国土地理院の標高タイル(テキスト)を標高の配列に変換する
+/// 1行が東西方向の256画素をコンマで区切ったもので、欠損値は`e`
+fn decode_gsi_text(bytes: &[u8]) -> anyhow::Result<Vec<f32>> {
+    std::str::from_utf8(bytes)?
+        .lines()
+        .filter(|line| !line.is_empty())
+        .flat_map(|line| line.split(','))
+        .map(|value| match value.trim() {
+            "e" => Ok(f32::NAN),
+            value => value
+                .parse::<f32>()
+                .map_err(|e| anyhow!("Invalid altitude {:?}: {}", value, e)),
+        })
+        .collect()
+}
+
+/// 全体のピクセル座標を含むタイルと、タイル内の画素の番号
+/// 経度方向は日付変更線をまたいで繋がっているものとし、緯度方向の範囲外では`None`を返す
+fn locate(z: u8, x: i64, y: i64) -> Option<((u32, u32), usize)> {
+    let size = TILE_SIZE << z;
+    if y < 0 || y >= size {
+        return None;
+    }
+    let x = x.rem_euclid(size);
+
+    let tile = ((x / TILE_SIZE) as u32, (y / TILE_SIZE) as u32);
+    Some((tile, ((y % TILE_SIZE) * TILE_SIZE + x % TILE_SIZE) as usize))
+}
+
+/// 小数を含むピクセル座標(x, y)の値を補間する
+/// 画素(i, j)の値は(i + 0.5, j + 0.5)の位置の値とし、`pixel`は欠損値や範囲外の画素で`None`を返す
+pub(crate) fn interpolate(
+    interpolation: Interpolation,
+    x: f64,
+    y: f64,
+    pixel: impl Fn(i64, i64) -> Option<f32>,
+) -> Option<f32> {
+    match interpolation {
+        Interpolation::Nearest => pixel(x.floor() as i64, y.floor() as i64),
+        Interpolation::Bilinear => bilinear(x, y, &pixel),
+        Interpolation::Bicubic => bicubic(x, y, &pixel).or_else(|| bilinear(x, y, &pixel)),
+    }
+}
+
+/// 双線形補間
+/// 欠損値の画素は除いて重みを付け直すが、最も近い画素が欠損値の場合は`None`とする
+fn bilinear(x: f64, y: f64, pixel: &impl Fn(i64, i64) -> Option<f32>) -> Option<f32> {
+    pixel(x.floor() as i64, y.floor() as i64)?;
+
+    // 画素の値は画素の中心の標高を表す
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    let mut sum = 0.;
+    let mut weight_sum = 0.;
+    for (dx, dy, weight) in [
+        (0, 0, (1. - fx) * (1. - fy)),
+        (1, 0, fx * (1. - fy)),
+        (0, 1, (1. - fx) * fy),
+        (1, 1, fx * fy),
+    ] {
+        if let Some(value) = pixel(x0 + dx, y0 + dy) {
+            sum += value as f64 * weight;
+            weight_sum += weight;
+        }
+    }
+
+    (weight_sum > 0.).then(|| (sum / weight_sum) as f32)
+}
+
+/// 双三次補間 (Catmull-Rom)
+/// 周囲16画素に欠損値が含まれる場合は`None`を返す
+fn bicubic(x: f64, y: f64, pixel: &impl Fn(i64, i64) -> Option<f32>) -> Option<f32> {
+    let (x, y) = (x - 0.5, y - 0.5);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let (x0, y0) = (x0 as i64, y0 as i64);
+
+    let mut rows = [0.; 4];
+    for (j, row) in rows.iter_mut().enumerate() {
+        let mut values = [0.; 4];
+        for (i, value) in values.iter_mut().enumerate() {
+            *value = pixel(x0 + i as i64 - 1, y0 + j as i64 - 1)? as f64;
+        }
+        *row = cubic(values, fx);
+    }
+
+    Some(cubic(rows, fy) as f32)
+}
+
+/// 緯度経度(度)から小数を含む全体のピクセル座標を求める (`ll2pixel`と同じ投影)
+fn pixel_position(long: f64, lat: f64, z: u8) -> (f64, f64) {
+    const L: f64 = 85.05112878;
+    let (long, lat) = (long.to_radians(), lat.to_radians());
+
+    let scale = 2_f64.powi(z as i32 + 7);
+    let x = scale * (long / PI + 1.);
+    let y = (scale / PI) * (-(lat.sin().atanh()) + (L * PI / 180.).sin().atanh());
+
+    (x, y)
+}
+
+/// Catmull-Romの3次補間
+fn cubic([p0, p1, p2, p3]: [f64; 4], t: f64) -> f64 {
+    p1 + 0.5
+        * t
+        * (p2 - p0 + t * (2. * p0 - 5. * p1 + 4. * p2 - p3 + t * (3. * (p1 - p2) + p3 - p0)))
+}
@@ -0,0 +1,200 @@
+use std::cmp::Ordering;
+use std::collections::BinaryHeap;
+use std::path::Path;
+
+use indicatif::ProgressBar;
+use rustc_hash::FxHashSet;
+
+use crate::graph::{RiverGraph, Table};
+use crate::update::{parse_link_key, read_rows, write_rows};
+use crate::DirectionArgs;
+
+/// `network direction`サブコマンド用の関数
+/// 河口からのネットワーク上の距離を求め、遠い側から近い側へ流れるようにリンクの向きを揃える
+pub fn infer_direction(args: &DirectionArgs) {
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let DirectionArgs {
+        network,
+        mouth_altitude,
+        max_rise,
+    } = args;
+    let nodes_path = Path::new(&network.nodes);
+    let links_path = Path::new(&network.links);
+
+    spinner.set_message("Reading nodes and links...");
+    let nodes = Table::read(nodes_path);
+    let mut links = Table::read(links_path);
+    let graph = RiverGraph::new(&nodes, &links);
+
+    spinner.set_message("Finding river mouths...");
+    let outlets = find_outlets(&graph, *mouth_altitude);
+
+    spinner.set_message("Calculating distances from river mouths...");
+    let distances = distance_to_outlets(&graph, &outlets);
+
+    spinner.set_message("Orienting links...");
+    let altitude = |node: usize| graph.altitudes[node];
+    let mut reversed = vec![false; graph.links.len()];
+    let mut ambiguous = vec![false; graph.links.len()];
+    for (i, link) in graph.links.iter().enumerate() {
+        let (d_start, d_end) = (distances[link.start], distances[link.end]);
+        reversed[i] = match d_start.total_cmp(&d_end) {
+            Ordering::Greater => false,
+            Ordering::Less => true,
+            // ループなどで河口からの距離が等しい場合は標高で決める (標高が無い場合は元の向きのまま)
+            Ordering::Equal => {
+                ambiguous[i] = true;
+                altitude(link.start)
+                    .zip(altitude(link.end))
+                    .is_some_and(|(start, end)| start < end)
+            }
+        };
+
+        // 下流側の方が許容値を超えて高い場合は、方向が疑わしいものとして扱う
+        // どちらかの標高が無い場合は判定しない
+        let (upstream, downstream) = if reversed[i] {
+            (link.end, link.start)
+        } else {
+            (link.start, link.end)
+        };
+        if altitude(upstream)
+            .zip(altitude(downstream))
+            .is_some_and(|(upstream, downstream)| downstream - upstream > *max_rise)
+        {
+            ambiguous[i] = true;
+        }
+    }
+
+    spinner.set_message("Writing links...");
+    let start_column = links.expect_column(":START_ID");
+    let end_column = links.expect_column(":END_ID");
+    let mut reversed_keys = FxHashSet::default();
+    for (i, link) in graph.links.iter().enumerate() {
+        if reversed[i] {
+            let row = &mut links.rows[link.row];
+            reversed_keys.insert((graph.ids[link.start], graph.ids[link.end]));
+            row.swap(start_column, end_column);
+        }
+    }
+    // 標高差と方位角も流下方向に合わせる
+    graph.write_link_geometry(&mut links, &reversed);
+    links.set_column(":TYPE", links.rows.iter().map(|_| "FLOWS_TO".to_string()).collect::<Vec<_>>());
+    links.set_column("ambiguous:boolean", ambiguous.iter().map(bool::to_string).collect::<Vec<_>>());
+    links.write(links_path);
+
+    // 差分更新でリンクを照合できるよう、タイルとリンクの対応の向きも揃える
+    let index_path = links_path.with_file_name("river_tile_index.csv");
+    if index_path.exists() {
+        let (header, rows) = read_rows(&index_path);
+        let rows = rows
+            .into_iter()
+            .map(|row| {
+                let mut iter = row.split(',');
+                let tile = iter.next().unwrap_or_default();
+                let (id1, id2) = parse_link_key(iter);
+                if reversed_keys.contains(&(id1, id2)) {
+                    format!("{tile},{id2},{id1}")
+                } else {
+                    row
+                }
+            })
+            .collect::<Vec<_>>();
+        write_rows(&index_path, &header, &rows);
+    }
+
+    spinner.finish_with_message(format!(
+        "Oriented {} links from {} river mouths ({} reversed, {} ambiguous)",
+        graph.links.len(),
+        outlets.len(),
+        reversed.iter().filter(|r| **r).count(),
+        ambiguous.iter().filter(|a| **a).count(),
+    ));
+}
+
+/// 河口とみなすノードを探す
+/// 標高が`mouth_altitude`以下の末端のノードを河口とし、河口が無い連結成分では1つのノードを河口とする
+/// その場合は末端のノードを優先し、標高が得られない(海上などでDEMが無い)ノード、最も低いノードの順に選ぶ
+fn find_outlets(graph: &RiverGraph, mouth_altitude: f32) -> Vec<usize> {
+    let (component, count) = graph.components();
+    let mut has_mouth = vec![false; count];
+    let mut lowest = vec![None::<(bool, bool, f32, usize)>; count];
+    let mut outlets = Vec::new();
+
+    for node in 0..graph.node_count() {
+        let is_end = graph.degree(node) == 1;
+        let altitude = graph.altitudes[node];
+        if is_end && altitude.is_some_and(|altitude| altitude <= mouth_altitude) {
+            has_mouth[component[node]] = true;
+            outlets.push(node);
+        }
+
+        // 河口が無い場合の候補を(末端でない, 標高がある, 標高)の小さい順に記録する
+        let candidate = (!is_end, altitude.is_some(), altitude.unwrap_or(0.), node);
+        let current = &mut lowest[component[node]];
+        if current.is_none_or(|current| {
+            (candidate.0, candidate.1, candidate.2).partial_cmp(&(current.0, current.1, current.2))
+                == Some(Ordering::Less)
+        }) {
+            *current = Some(candidate);
+        }
+    }
+
+    outlets.extend(
+        (0..count)
+            .filter(|c| !has_mouth[*c])
+            .filter_map(|c| lowest[c].map(|(_, _, _, node)| node)),
+    );
+    outlets
+}
+
+/// ダイクストラ法の探索状態 (距離の小さい順に取り出す)
+struct State(f64, usize);
+
+impl PartialEq for State {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl Eq for State {}
+
+impl PartialOrd for State {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for State {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.total_cmp(&self.0).then_with(|| other.1.cmp(&self.1))
+    }
+}
+
+/// 最も近い河口までのネットワーク上の距離[m]
+fn distance_to_outlets(graph: &RiverGraph, outlets: &[usize]) -> Vec<f64> {
+    let mut distances = vec![f64::INFINITY; graph.node_count()];
+    let mut heap = BinaryHeap::new();
+    for &outlet in outlets {
+        distances[outlet] = 0.;
+        heap.push(State(0., outlet));
+    }
+
+    while let Some(State(distance, node)) = heap.pop() {
+        if distance > distances[node] {
+            continue;
+        }
+        for &link in &graph.adjacency[node] {
+            let link = graph.links[link];
+            let other = link.other(node);
+            let next = distance + link.length;
+            if next < distances[other] {
+                distances[other] = next;
+                heap.push(State(next, other));
+            }
+        }
+    }
+
+    distances
+}
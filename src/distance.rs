@@ -0,0 +1,774 @@
+use clap::ValueEnum;
+use coordinate_transformer::{ll2jpr, JprOrigin};
+
+use crate::collect::haversine_distance_m;
+use crate::DistanceArgs;
+
+/// リンクの長さの求め方
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum DistanceModel {
+    /// 半径6371kmの球面上の大円距離 (ハヴァーサイン)
+    Haversine,
+    /// 楕円体上の測地線長 (Vincentyの反復解法、収束しない場合はKarneyの方法)
+    Vincenty,
+    /// 楕円体上の測地線長 (Karneyの方法)
+    Karney,
+    /// JGD2011の平面直角座標系での距離
+    Plane,
+}
+
+/// 測地線長を求める楕円体
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Ellipsoid {
+    /// GRS80 (JGD2011)
+    Grs80,
+    /// WGS84
+    Wgs84,
+}
+
+impl Ellipsoid {
+    /// (長半径[m], 扁平率)
+    fn parameters(self) -> (f64, f64) {
+        match self {
+            Ellipsoid::Grs80 => (6378137., 1. / 298.257222101),
+            Ellipsoid::Wgs84 => (6378137., 1. / 298.257223563),
+        }
+    }
+}
+
+/// 2地点間の距離を求める
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct DistanceMeasure {
+    model: DistanceModel,
+    geodesic: Geodesic,
+    /// `plane`で使う平面直角座標系 (`--plane-zone`)
+    plane_zone: Option<JprOrigin>,
+}
+
+impl DistanceMeasure {
+    pub fn new(args: &DistanceArgs) -> Self {
+        let DistanceArgs {
+            distance: model,
+            ellipsoid,
+            plane_zone,
+        } = *args;
+        Self {
+            model,
+            geodesic: Geodesic::new(ellipsoid),
+            plane_zone: plane_zone
+                .map(|zone| JprOrigin::parse(zone).unwrap_or_else(|_| panic!("Invalid plane rectangular zone: {}", zone))),
+        }
+    }
+
+    /// 2地点(経度, 緯度)[度]間の距離[m]
+    pub fn distance_m(&self, (long1, lat1): (f64, f64), (long2, lat2): (f64, f64)) -> f64 {
+        match self.model {
+            DistanceModel::Haversine => haversine_distance_m(
+                long1.to_radians(),
+                lat1.to_radians(),
+                long2.to_radians(),
+                lat2.to_radians(),
+            ),
+            DistanceModel::Vincenty => self
+                .geodesic
+                .vincenty(lat1, long1, lat2, long2)
+                .unwrap_or_else(|| self.geodesic.karney(lat1, long1, lat2, long2)),
+            DistanceModel::Karney => self.geodesic.karney(lat1, long1, lat2, long2),
+            DistanceModel::Plane => {
+                let origin = self
+                    .plane_zone
+                    .expect("--plane-zone is required for --distance plane");
+                let (y1, x1) = ll2jpr((long1.to_radians(), lat1.to_radians()), origin);
+                let (y2, x2) = ll2jpr((long2.to_radians(), lat2.to_radians()), origin);
+                (y2 - y1).hypot(x2 - x1)
+            }
+        }
+    }
+}
+
+const TOL0: f64 = f64::EPSILON;
+const MAX_ITERATIONS_NEWTON: usize = 20;
+const MAX_ITERATIONS: usize = MAX_ITERATIONS_NEWTON + f64::MANTISSA_DIGITS as usize + 10;
+/// 対蹠点付近で子午線に沿った切れ目とみなす緯度方向の閾値
+const TOL1: f64 = 200. * TOL0;
+
+/// 回転楕円体上の測地線
+/// Karneyの方法はC. F. F. Karney, "Algorithms for geodesics", J. Geodesy 87 (2013)とGeographicLibに従い、6次の級数を使う
+/// ただし対蹠点付近の初期値の改良(アステロイド)は省き、二分法で収束させる
+#[derive(Debug, Clone, Copy)]
+struct Geodesic {
+    /// 長半径
+    a: f64,
+    /// 扁平率
+    f: f64,
+    /// 1 - f
+    f1: f64,
+    /// 第二離心率の2乗
+    ep2: f64,
+    /// 第三扁平率
+    n: f64,
+    /// 短半径
+    b: f64,
+    /// 短い測地線とみなす閾値
+    etol2: f64,
+    /// A3の係数
+    a3x: [f64; 6],
+    /// C3の係数
+    c3x: [f64; 15],
+}
+
+/// Λ12の計算結果
+struct Lambda {
+    /// 経度差の誤差
+    v: f64,
+    /// vのα1による微分 (求めない場合はNaN)
+    dv: f64,
+    sig12: f64,
+    ssig1: f64,
+    csig1: f64,
+    ssig2: f64,
+    csig2: f64,
+    eps: f64,
+}
+
+impl Geodesic {
+    fn new(ellipsoid: Ellipsoid) -> Self {
+        let (a, f) = ellipsoid.parameters();
+        let f1 = 1. - f;
+        let e2 = f * (2. - f);
+        let n = f / (2. - f);
+        let tol2 = TOL0.sqrt();
+
+        // A3, C3の係数 (nの多項式)
+        const A3_COEFF: [f64; 18] = [
+            -3., 128., -2., -3., 64., -1., -3., -1., 16., 3., -1., -2., 8., 1., -1., 2., 1., 1.,
+        ];
+        const C3_COEFF: [f64; 45] = [
+            3., 128., 2., 5., 128., -1., 3., 3., 64., -1., 0., 1., 8., -1., 1., 4., 5., 256., 1., 3., 128., -3.,
+            -2., 3., 64., 1., -3., 2., 32., 7., 512., -10., 9., 384., 5., -9., 5., 192., 7., 512., -14., 7., 512.,
+            21., 2560.,
+        ];
+        let mut a3x = [0.; 6];
+        let mut o = 0;
+        for (k, j) in (0..6).rev().enumerate() {
+            let m = (6 - j - 1).min(j);
+            a3x[k] = polyval(&A3_COEFF[o..=o + m], n) / A3_COEFF[o + m + 1];
+            o += m + 2;
+        }
+        let mut c3x = [0.; 15];
+        let (mut o, mut k) = (0, 0);
+        for l in 1..6 {
+            for j in (l..6).rev() {
+                let m = (6 - j - 1).min(j);
+                c3x[k] = polyval(&C3_COEFF[o..=o + m], n) / C3_COEFF[o + m + 1];
+                o += m + 2;
+                k += 1;
+            }
+        }
+
+        Self {
+            a,
+            f,
+            f1,
+            ep2: e2 / (f1 * f1),
+            n,
+            b: a * f1,
+            etol2: 0.1 * tol2 / (f.abs().max(0.001) * (1. - f / 2.).min(1.) / 2.).sqrt(),
+            a3x,
+            c3x,
+        }
+    }
+
+    /// Vincentyの反復解法による測地線長[m] (収束しない場合はNone)
+    fn vincenty(&self, lat1: f64, long1: f64, lat2: f64, long2: f64) -> Option<f64> {
+        let Self { a, b, f, .. } = *self;
+        let l = ang_normalize(long2 - long1).to_radians();
+        // 更成緯度
+        let (su1, cu1) = (self.f1 * lat1.to_radians().tan()).atan().sin_cos();
+        let (su2, cu2) = (self.f1 * lat2.to_radians().tan()).atan().sin_cos();
+
+        let mut lambda = l;
+        for _ in 0..200 {
+            let (sl, cl) = lambda.sin_cos();
+            let ss = (cu2 * sl).hypot(cu1 * su2 - su1 * cu2 * cl);
+            // 同じ地点
+            if ss == 0. {
+                return Some(0.);
+            }
+            let cs = su1 * su2 + cu1 * cu2 * cl;
+            let sigma = ss.atan2(cs);
+            let sa = cu1 * cu2 * sl / ss;
+            let c2a = 1. - sa * sa;
+            // 赤道上の測地線ではcos(2σm) = 0とする
+            let c2sm = if c2a != 0. { cs - 2. * su1 * su2 / c2a } else { 0. };
+            let c = f / 16. * c2a * (4. + f * (4. - 3. * c2a));
+            let previous = lambda;
+            lambda = l + (1. - c) * f * sa * (sigma + c * ss * (c2sm + c * cs * (-1. + 2. * c2sm * c2sm)));
+            if lambda.abs() > std::f64::consts::PI {
+                return None;
+            }
+
+            if (lambda - previous).abs() < 1e-12 {
+                let u2 = c2a * (a * a - b * b) / (b * b);
+                let big_a = 1. + u2 / 16384. * (4096. + u2 * (-768. + u2 * (320. - 175. * u2)));
+                let big_b = u2 / 1024. * (256. + u2 * (-128. + u2 * (74. - 47. * u2)));
+                let d_sigma = big_b
+                    * ss
+                    * (c2sm
+                        + big_b / 4.
+                            * (cs * (-1. + 2. * c2sm * c2sm)
+                                - big_b / 6. * c2sm * (-3. + 4. * ss * ss) * (-3. + 4. * c2sm * c2sm)));
+                return Some(b * big_a * (sigma - d_sigma));
+            }
+        }
+
+        None
+    }
+
+    /// Karneyの方法による測地線長[m]
+    fn karney(&self, lat1: f64, long1: f64, lat2: f64, long2: f64) -> f64 {
+        let Self { b, f, f1, ep2, .. } = *self;
+
+        let lon12 = ang_round(ang_normalize(long2 - long1).abs());
+        let lam12 = lon12.to_radians();
+        let (slam12, clam12) = sincosd(lon12);
+
+        // |lat1| >= |lat2|かつlat1 <= 0となるように入れ替える
+        let (mut lat1, mut lat2) = (ang_round(lat1), ang_round(lat2));
+        if lat1.abs() < lat2.abs() {
+            std::mem::swap(&mut lat1, &mut lat2);
+        }
+        if lat1 > 0. {
+            lat1 = -lat1;
+            lat2 = -lat2;
+        }
+
+        // 更成緯度β
+        let reduced = |lat: f64| {
+            let (s, c) = sincosd(lat);
+            let (s, c) = norm2(f1 * s, c);
+            (s, c.max(f64::MIN_POSITIVE.sqrt()))
+        };
+        let (sbet1, cbet1) = reduced(lat1);
+        let (mut sbet2, mut cbet2) = reduced(lat2);
+        if cbet1 < -sbet1 {
+            if cbet2 == cbet1 {
+                sbet2 = sbet1.copysign(sbet2);
+            }
+        } else if sbet2.abs() == -sbet1 {
+            cbet2 = cbet1;
+        }
+        let dn1 = (1. + ep2 * sbet1 * sbet1).sqrt();
+        let dn2 = (1. + ep2 * sbet2 * sbet2).sqrt();
+
+        // 子午線に沿った測地線
+        if lat1 == -90. || slam12 == 0. {
+            let (ssig1, csig1) = (sbet1, clam12 * cbet1);
+            let (ssig2, csig2) = (sbet2, cbet2);
+            let sig12 = (csig1 * ssig2 - ssig1 * csig2)
+                .max(0.)
+                .atan2(csig1 * csig2 + ssig1 * ssig2);
+            let (s12, m12) = self.lengths(self.n, sig12, (ssig1, csig1, dn1), (ssig2, csig2, dn2));
+            if sig12 < 1. || m12 >= 0. {
+                if sig12 < 3. * f64::MIN_POSITIVE.sqrt() || (sig12 < TOL0 && (s12 < 0. || m12 < 0.)) {
+                    return 0.;
+                }
+                return s12 * b;
+            }
+        }
+
+        // 赤道に沿った測地線
+        if sbet1 == 0. && (f <= 0. || 180. - lon12 >= f * 180.) {
+            return self.a * lam12;
+        }
+
+        let (sig12, mut salp1, mut calp1, dnm) =
+            self.inverse_start((sbet1, cbet1), (sbet2, cbet2), lam12, (slam12, clam12));
+        // 短い測地線
+        if sig12 >= 0. {
+            return sig12 * b * dnm;
+        }
+
+        // α1をNewton法(失敗した場合は二分法)で求める
+        let (mut salp1a, mut calp1a) = (f64::MIN_POSITIVE.sqrt(), 1.);
+        let (mut salp1b, mut calp1b) = (f64::MIN_POSITIVE.sqrt(), -1.);
+        let (mut tripn, mut tripb) = (false, false);
+        let mut lambda = None;
+        for i in 0..MAX_ITERATIONS {
+            let current = self.lambda12(
+                (sbet1, cbet1, dn1),
+                (sbet2, cbet2, dn2),
+                (salp1, calp1),
+                (slam12, clam12),
+                i < MAX_ITERATIONS_NEWTON,
+            );
+            let v = current.v;
+            let dv = current.dv;
+            lambda = Some(current);
+            // 誤差が許容値未満になるか、NaNになった場合は打ち切る
+            let tolerance = if tripn { 8. } else { 1. } * TOL0;
+            if tripb || v.is_nan() || v.abs() < tolerance {
+                break;
+            }
+            if v > 0. && (i > MAX_ITERATIONS_NEWTON || calp1 / salp1 > calp1b / salp1b) {
+                (salp1b, calp1b) = (salp1, calp1);
+            } else if v < 0. && (i > MAX_ITERATIONS_NEWTON || calp1 / salp1 < calp1a / salp1a) {
+                (salp1a, calp1a) = (salp1, calp1);
+            }
+            if i < MAX_ITERATIONS_NEWTON && dv > 0. {
+                let dalp1 = -v / dv;
+                if dalp1.abs() < std::f64::consts::PI {
+                    let (sdalp1, cdalp1) = dalp1.sin_cos();
+                    let nsalp1 = salp1 * cdalp1 + calp1 * sdalp1;
+                    if nsalp1 > 0. {
+                        (salp1, calp1) = norm2(nsalp1, calp1 * cdalp1 - salp1 * sdalp1);
+                        tripn = v.abs() <= 16. * TOL0;
+                        continue;
+                    }
+                }
+            }
+            (salp1, calp1) = norm2((salp1a + salp1b) / 2., (calp1a + calp1b) / 2.);
+            tripn = false;
+            let tolb = TOL0 * TOL0.sqrt();
+            tripb = (salp1a - salp1).abs() + (calp1a - calp1) < tolb || (salp1 - salp1b).abs() + (calp1 - calp1b) < tolb;
+        }
+
+        let Lambda {
+            sig12,
+            ssig1,
+            csig1,
+            ssig2,
+            csig2,
+            eps,
+            ..
+        } = lambda.unwrap();
+        let (s12, _) = self.lengths(eps, sig12, (ssig1, csig1, dn1), (ssig2, csig2, dn2));
+        s12 * b
+    }
+
+    /// 短半径を単位とする測地線長と換算長
+    fn lengths(&self, eps: f64, sig12: f64, (ssig1, csig1, dn1): (f64, f64, f64), (ssig2, csig2, dn2): (f64, f64, f64)) -> (f64, f64) {
+        let c1 = c1f(eps);
+        let c2 = c2f(eps);
+        let a1 = a1m1f(eps);
+        let a2 = a2m1f(eps);
+        let m0x = a1 - a2;
+        let (a1, a2) = (1. + a1, 1. + a2);
+
+        let b1 = sin_cos_series(ssig2, csig2, &c1) - sin_cos_series(ssig1, csig1, &c1);
+        let b2 = sin_cos_series(ssig2, csig2, &c2) - sin_cos_series(ssig1, csig1, &c2);
+        let s12 = a1 * (sig12 + b1);
+        let j12 = m0x * sig12 + (a1 * b1 - a2 * b2);
+        let m12 = dn2 * (csig1 * ssig2) - dn1 * (ssig1 * csig2) - csig1 * csig2 * j12;
+        (s12, m12)
+    }
+
+    /// α1の初期値を求める
+    /// 短い測地線の場合は(σ12, sinα1, cosα1, 平均のdn)、そうでない場合はσ12を負の値とする
+    fn inverse_start(
+        &self,
+        (sbet1, cbet1): (f64, f64),
+        (sbet2, cbet2): (f64, f64),
+        lam12: f64,
+        (slam12, clam12): (f64, f64),
+    ) -> (f64, f64, f64, f64) {
+        let sbet12 = sbet2 * cbet1 - cbet2 * sbet1;
+        let cbet12 = cbet2 * cbet1 + sbet2 * sbet1;
+        let sbet12a = sbet2 * cbet1 + cbet2 * sbet1;
+        let shortline = cbet12 >= 0. && sbet12 < 0.5 && cbet2 * lam12 < 0.5;
+
+        let mut dnm = f64::NAN;
+        let (somg12, comg12) = if shortline {
+            let mut sbetm2 = (sbet1 + sbet2).powi(2);
+            sbetm2 /= sbetm2 + (cbet1 + cbet2).powi(2);
+            dnm = (1. + self.ep2 * sbetm2).sqrt();
+            (lam12 / (self.f1 * dnm)).sin_cos()
+        } else {
+            (slam12, clam12)
+        };
+
+        let salp1 = cbet2 * somg12;
+        let calp1 = if comg12 >= 0. {
+            sbet12 + cbet2 * sbet1 * somg12 * somg12 / (1. + comg12)
+        } else {
+            sbet12a - cbet2 * sbet1 * somg12 * somg12 / (1. - comg12)
+        };
+        let ssig12 = salp1.hypot(calp1);
+        let csig12 = sbet1 * sbet2 + cbet1 * cbet2 * comg12;
+
+        let mut sig12 = -1.;
+        let (mut salp1, mut calp1) = (salp1, calp1);
+        if shortline && ssig12 < self.etol2 {
+            sig12 = ssig12.atan2(csig12);
+        } else if self.n.abs() < 0.1
+            && csig12 < 0.
+            && ssig12 < 6. * self.n.abs() * std::f64::consts::PI * cbet1 * cbet1
+        {
+            // ほぼ対蹠点の場合は球面近似が悪いので、対蹠点を原点とする座標(x, y)でアステロイドの方程式を解く
+            // (扁平な楕円体のみを扱うので、xは経度方向、yは緯度方向とする)
+            let lam12x = (-slam12).atan2(-clam12);
+            let k2 = sbet1 * sbet1 * self.ep2;
+            let eps = k2 / (2. * (1. + (1. + k2).sqrt()) + k2);
+            let lamscale = self.f * cbet1 * self.a3f(eps) * std::f64::consts::PI;
+            let betscale = lamscale * cbet1;
+            let x = lam12x / lamscale;
+            let y = sbet12a / betscale;
+
+            let xthresh = 1000. * TOL0.sqrt();
+            if y > -TOL1 && x > -1. - xthresh {
+                // 子午線に沿った切れ目の近く
+                salp1 = (-x).min(1.);
+                calp1 = -(1. - salp1 * salp1).sqrt();
+            } else {
+                let k = astroid(x, y);
+                let omg12a = lamscale * (-x * k / (1. + k));
+                let (somg12, comg12) = (omg12a.sin(), -omg12a.cos());
+                salp1 = cbet2 * somg12;
+                calp1 = sbet12a - cbet2 * sbet1 * somg12 * somg12 / (1. - comg12);
+            }
+        }
+        let (salp1, calp1) = if salp1 > 0. { norm2(salp1, calp1) } else { (1., 0.) };
+        (sig12, salp1, calp1, dnm)
+    }
+
+    /// α1から求めた経度差と、目的の経度差との誤差
+    fn lambda12(
+        &self,
+        (sbet1, cbet1, dn1): (f64, f64, f64),
+        (sbet2, cbet2, dn2): (f64, f64, f64),
+        (salp1, mut calp1): (f64, f64),
+        (slam120, clam120): (f64, f64),
+        diffp: bool,
+    ) -> Lambda {
+        if sbet1 == 0. && calp1 == 0. {
+            calp1 = -f64::MIN_POSITIVE.sqrt();
+        }
+
+        let salp0 = salp1 * cbet1;
+        let calp0 = calp1.hypot(salp1 * sbet1);
+
+        let somg1 = salp0 * sbet1;
+        let comg1 = calp1 * cbet1;
+        let (ssig1, csig1) = norm2(sbet1, comg1);
+
+        let calp2 = if cbet2 != cbet1 || sbet2.abs() != -sbet1 {
+            ((calp1 * cbet1).powi(2)
+                + if cbet1 < -sbet1 {
+                    (cbet2 - cbet1) * (cbet1 + cbet2)
+                } else {
+                    (sbet1 - sbet2) * (sbet1 + sbet2)
+                })
+            .sqrt()
+                / cbet2
+        } else {
+            calp1.abs()
+        };
+
+        let somg2 = salp0 * sbet2;
+        let comg2 = calp2 * cbet2;
+        let (ssig2, csig2) = norm2(sbet2, comg2);
+
+        let sig12 = (csig1 * ssig2 - ssig1 * csig2)
+            .max(0.)
+            .atan2(csig1 * csig2 + ssig1 * ssig2);
+        let somg12 = (comg1 * somg2 - somg1 * comg2).max(0.);
+        let comg12 = comg1 * comg2 + somg1 * somg2;
+        let eta = (somg12 * clam120 - comg12 * slam120).atan2(comg12 * clam120 + somg12 * slam120);
+
+        let k2 = calp0 * calp0 * self.ep2;
+        let eps = k2 / (2. * (1. + (1. + k2).sqrt()) + k2);
+        let c3 = self.c3f(eps);
+        let b312 = sin_cos_series(ssig2, csig2, &c3) - sin_cos_series(ssig1, csig1, &c3);
+        let domg12 = -self.f * self.a3f(eps) * salp0 * (sig12 + b312);
+
+        let dv = if !diffp {
+            f64::NAN
+        } else if calp2 == 0. {
+            -2. * self.f1 * dn1 / sbet1
+        } else {
+            let (_, m12) = self.lengths(eps, sig12, (ssig1, csig1, dn1), (ssig2, csig2, dn2));
+            m12 * self.f1 / (calp2 * cbet2)
+        };
+
+        Lambda {
+            v: eta + domg12,
+            dv,
+            sig12,
+            ssig1,
+            csig1,
+            ssig2,
+            csig2,
+            eps,
+        }
+    }
+
+    fn a3f(&self, eps: f64) -> f64 {
+        polyval(&self.a3x, eps)
+    }
+
+    /// C3の係数 (添字1から5まで)
+    fn c3f(&self, eps: f64) -> [f64; 6] {
+        let mut c = [0.; 6];
+        let mut mult = 1.;
+        let mut o = 0;
+        for (l, c) in c.iter_mut().enumerate().skip(1) {
+            let m = 6 - l - 1;
+            mult *= eps;
+            *c = mult * polyval(&self.c3x[o..=o + m], eps);
+            o += m + 1;
+        }
+        c
+    }
+}
+
+/// アステロイドの方程式 x^2 / (1 + k)^2 + y^2 / k^2 = 1 の正の根k
+fn astroid(x: f64, y: f64) -> f64 {
+    let p = x * x;
+    let q = y * y;
+    let r = (p + q - 1.) / 6.;
+    if q == 0. && r <= 0. {
+        return 0.;
+    }
+
+    let s = p * q / 4.;
+    let r2 = r * r;
+    let r3 = r * r2;
+    let disc = s * (s + 2. * r3);
+    let mut u = r;
+    if disc >= 0. {
+        let mut t3 = s + r3;
+        t3 += if t3 < 0. { -disc.sqrt() } else { disc.sqrt() };
+        let t = t3.cbrt();
+        u += t + if t != 0. { r2 / t } else { 0. };
+    } else {
+        let ang = (-disc).sqrt().atan2(-(s + r3));
+        u += 2. * r * (ang / 3.).cos();
+    }
+    let v = (u * u + q).sqrt();
+    let uv = if u < 0. { q / (v - u) } else { u + v };
+    let w = (uv - q) / (2. * v);
+    uv / ((uv + w * w).sqrt() + w)
+}
+
+/// A1 - 1
+fn a1m1f(eps: f64) -> f64 {
+    let t = polyval(&[1., 4., 64., 0.], eps * eps) / 256.;
+    (t + eps) / (1. - eps)
+}
+
+/// A2 - 1
+fn a2m1f(eps: f64) -> f64 {
+    let t = polyval(&[-11., -28., -192., 0.], eps * eps) / 256.;
+    (t - eps) / (1. + eps)
+}
+
+/// C1の係数 (添字1から6まで)
+fn c1f(eps: f64) -> [f64; 7] {
+    const COEFF: [f64; 18] = [
+        -1., 6., -16., 32., -9., 64., -128., 2048., 9., -16., 768., 3., -5., 512., -7., 1280., -7., 2048.,
+    ];
+    series_coefficients(&COEFF, eps)
+}
+
+/// C2の係数 (添字1から6まで)
+fn c2f(eps: f64) -> [f64; 7] {
+    const COEFF: [f64; 18] = [
+        1., 2., 16., 32., 35., 64., 384., 2048., 15., 80., 768., 7., 35., 512., 63., 1280., 77., 2048.,
+    ];
+    series_coefficients(&COEFF, eps)
+}
+
+/// epsの2乗の多項式と分母の組から、epsの冪級数の係数を求める
+fn series_coefficients(coeff: &[f64], eps: f64) -> [f64; 7] {
+    let eps2 = eps * eps;
+    let mut c = [0.; 7];
+    let mut d = eps;
+    let mut o = 0;
+    for (l, c) in c.iter_mut().enumerate().skip(1) {
+        let m = (6 - l) / 2;
+        *c = d * polyval(&coeff[o..=o + m], eps2) / coeff[o + m + 1];
+        o += m + 2;
+        d *= eps;
+    }
+    c
+}
+
+/// 次数の高い順の係数の多項式の値
+fn polyval(coeff: &[f64], x: f64) -> f64 {
+    coeff.iter().fold(0., |y, c| y * x + c)
+}
+
+/// Σ c[k] sin(2kx) (c[0]は使わない) をClenshawの方法で求める
+fn sin_cos_series(sinx: f64, cosx: f64, c: &[f64]) -> f64 {
+    let ar = 2. * (cosx - sinx) * (cosx + sinx);
+    let mut k = c.len() - 1;
+    let (mut y0, mut y1) = (0., 0.);
+    if k % 2 == 1 {
+        y0 = c[k];
+        k -= 1;
+    }
+    while k > 0 {
+        y1 = ar * y0 - y1 + c[k];
+        y0 = ar * y1 - y0 + c[k - 1];
+        k -= 2;
+    }
+    2. * sinx * cosx * y0
+}
+
+fn norm2(s: f64, c: f64) -> (f64, f64) {
+    let r = s.hypot(c);
+    (s / r, c / r)
+}
+
+/// 度単位の角度を(-180, 180]に正規化する
+fn ang_normalize(x: f64) -> f64 {
+    let y = x.rem_euclid(360.);
+    if y > 180. {
+        y - 360.
+    } else {
+        y
+    }
+}
+
+/// 0に非常に近い角度を丸めて、桁落ちを防ぐ
+fn ang_round(x: f64) -> f64 {
+    let z = 1. / 16.;
+    let y = x.abs();
+    let y = if y < z { z - (z - y) } else { y };
+    y.copysign(x)
+}
+
+/// 度単位の角度のsinとcos (90度の倍数では厳密な値を返す)
+fn sincosd(x: f64) -> (f64, f64) {
+    let r = x % 360.;
+    let q = (r / 90.).round();
+    let (s, c) = (r - 90. * q).to_radians().sin_cos();
+    match (q as i64).rem_euclid(4) {
+        0 => (s, c),
+        1 => (c, -s),
+        2 => (-s, -c),
+        _ => (-c, s),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn measure(distance: DistanceModel, ellipsoid: Ellipsoid) -> DistanceMeasure {
+        DistanceMeasure::new(&DistanceArgs {
+            distance,
+            ellipsoid,
+            plane_zone: (distance == DistanceModel::Plane).then_some(6),
+        })
+    }
+
+    fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+        assert!(
+            (actual - expected).abs() <= tolerance,
+            "expected {expected} ± {tolerance}, got {actual}"
+        );
+    }
+
+    #[test]
+    fn jfk_to_lhr() {
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        assert_close(geodesic.karney(40.6, -73.8, 51.6, -0.5), 5551759.4, 0.05);
+        assert_close(geodesic.vincenty(40.6, -73.8, 51.6, -0.5).unwrap(), 5551759.4, 0.05);
+    }
+
+    #[test]
+    fn one_degree_along_equator() {
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        assert_close(geodesic.karney(0., 0., 0., 1.), 111319.4908, 1e-4);
+        assert_close(geodesic.vincenty(0., 0., 0., 1.).unwrap(), 111319.4908, 1e-4);
+    }
+
+    #[test]
+    fn one_degree_along_meridian() {
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        assert_close(geodesic.karney(0., 0., 1., 0.), 110574.3886, 1e-4);
+        assert_close(geodesic.vincenty(0., 0., 1., 0.).unwrap(), 110574.3886, 1e-4);
+    }
+
+    #[test]
+    fn pole_to_pole() {
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        assert_close(geodesic.karney(-90., 0., 90., 0.), 20003931.4586, 1e-3);
+    }
+
+    #[test]
+    fn near_antipodal() {
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        assert_close(geodesic.karney(0., 0., 0.5, 179.5), 19936288.579, 1e-3);
+        let vincenty = measure(DistanceModel::Vincenty, Ellipsoid::Wgs84);
+        assert_close(vincenty.distance_m((0., 0.), (179.5, 0.5)), 19936288.579, 1e-3);
+    }
+
+    #[test]
+    fn near_antipodal_off_equator() {
+        // GeographicLibの値
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        assert_close(geodesic.karney(0., 0., 0.1, 179.9), 19992082.108, 1e-3);
+        assert_close(geodesic.karney(-30., 0., 29.9, 179.8), 19989832.828, 1e-3);
+        assert_close(geodesic.karney(10., 0., -10.01, 179.99), 20002816.304, 1e-3);
+        assert_close(geodesic.karney(45., 10., -44.999, -170.001), 20003820.235, 1e-3);
+    }
+
+    #[test]
+    fn near_antipodal_start() {
+        // ほぼ対蹠点ではアステロイドの解から求めた初期値が解に十分近い
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        let reduced = |lat: f64| {
+            let (s, c) = sincosd(lat);
+            let (s, c) = norm2(geodesic.f1 * s, c);
+            (s, c, (1. + geodesic.ep2 * s * s).sqrt())
+        };
+        for (lat1, lat2, lon12) in [(0., 0.5, 179.5), (0., 0.1, 179.9), (-30., 29.9, 179.8), (-10., 10.01, 179.99)] {
+            let (sbet1, cbet1, dn1) = reduced(lat1);
+            let (sbet2, cbet2, dn2) = reduced(lat2);
+            let lam12 = sincosd(lon12);
+            let (sig12, salp1, calp1, _) =
+                geodesic.inverse_start((sbet1, cbet1), (sbet2, cbet2), lon12.to_radians(), lam12);
+            assert!(sig12 < 0.);
+            let lambda = geodesic.lambda12((sbet1, cbet1, dn1), (sbet2, cbet2, dn2), (salp1, calp1), lam12, false);
+            assert!(lambda.v.abs() < 1e-5, "{lat1} {lat2} {lon12}: {}", lambda.v);
+        }
+    }
+
+    #[test]
+    fn vincenty_falls_back_to_karney() {
+        // 赤道上のほぼ対蹠点ではVincentyの解法が収束しない
+        let geodesic = Geodesic::new(Ellipsoid::Wgs84);
+        assert_eq!(geodesic.vincenty(0., 0., 0., 179.5), None);
+
+        let vincenty = measure(DistanceModel::Vincenty, Ellipsoid::Wgs84);
+        assert_eq!(
+            vincenty.distance_m((0., 0.), (179.5, 0.)),
+            geodesic.karney(0., 0., 0., 179.5)
+        );
+    }
+
+    #[test]
+    fn coincident_points() {
+        for model in DistanceModel::value_variants() {
+            let measure = measure(*model, Ellipsoid::Grs80);
+            assert_eq!(measure.distance_m((135.5, 35.), (135.5, 35.)), 0., "{:?}", model);
+        }
+    }
+
+    #[test]
+    fn haversine_one_degree() {
+        let haversine = measure(DistanceModel::Haversine, Ellipsoid::Grs80);
+        assert_close(haversine.distance_m((0., 0.), (1., 0.)), 6371000. * 1_f64.to_radians(), 1e-6);
+    }
+
+    #[test]
+    fn plane_near_origin() {
+        // 第6系の原点付近では、縮尺係数0.9999を掛けた測地線長に近い
+        let plane = measure(DistanceModel::Plane, Ellipsoid::Grs80);
+        let karney = measure(DistanceModel::Karney, Ellipsoid::Grs80);
+        let (p1, p2) = ((136., 36.), (136.01, 36.01));
+        assert_close(plane.distance_m(p1, p2), karney.distance_m(p1, p2) * 0.9999, 1e-3);
+    }
+}
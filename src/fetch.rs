@@ -0,0 +1,221 @@
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use reqwest::header::{ETAG, IF_MODIFIED_SINCE, IF_NONE_MATCH, LAST_MODIFIED};
+use reqwest::{Client, StatusCode};
+
+/// タイルを取得するクライアント
+/// `file://`で始まるURLやhttp(s)以外のパスはローカルのディレクトリから読み込む
+/// キャッシュディレクトリが指定されている場合は、取得したタイルをURLごとにディスクへ保存し、
+/// 次回以降はETag/Last-Modifiedによる再検証を行って変更があった場合のみダウンロードする
+#[derive(Clone)]
+pub struct TileFetcher {
+    client: Client,
+    cache_dir: Option<Arc<PathBuf>>,
+}
+
+/// キャッシュしたタイルの再検証に使う情報
+#[derive(Default)]
+struct CacheMeta {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+impl CacheMeta {
+    fn parse(s: &str) -> Self {
+        let mut meta = Self::default();
+        for line in s.lines() {
+            match line.split_once(": ") {
+                Some(("etag", v)) => meta.etag = Some(v.to_string()),
+                Some(("last-modified", v)) => meta.last_modified = Some(v.to_string()),
+                _ => {}
+            }
+        }
+        meta
+    }
+
+    fn serialize(&self) -> String {
+        let mut s = String::new();
+        if let Some(etag) = &self.etag {
+            s.push_str(&format!("etag: {etag}\n"));
+        }
+        if let Some(last_modified) = &self.last_modified {
+            s.push_str(&format!("last-modified: {last_modified}\n"));
+        }
+        s
+    }
+}
+
+impl TileFetcher {
+    pub fn new(cache_dir: Option<PathBuf>) -> Self {
+        Self {
+            client: Client::new(),
+            cache_dir: cache_dir.map(Arc::new),
+        }
+    }
+
+    /// URLの内容を取得する
+    /// タイルが存在しない(404)場合は`None`を返す
+    pub async fn fetch(&self, url: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        if let Some(path) = local_path(url) {
+            return read_local(&path).await;
+        }
+
+        let Some(cache_dir) = &self.cache_dir else {
+            return self.fetch_remote(url).await;
+        };
+
+        let body_path = cache_path(cache_dir, url);
+        let meta_path = sibling_path(&body_path, "meta");
+
+        let cached_meta = match tokio::fs::read_to_string(&meta_path).await {
+            Ok(s) if body_path.exists() => Some(CacheMeta::parse(&s)),
+            _ => None,
+        };
+
+        // キャッシュがある場合は条件付きリクエストで再検証する
+        let mut request = self.client.get(url);
+        if let Some(meta) = &cached_meta {
+            if let Some(etag) = &meta.etag {
+                request = request.header(IF_NONE_MATCH, etag);
+            }
+            if let Some(last_modified) = &meta.last_modified {
+                request = request.header(IF_MODIFIED_SINCE, last_modified);
+            }
+        }
+
+        let res = match request.send().await {
+            Ok(res) => res,
+            // 通信に失敗した場合でもキャッシュがあればそれを使う
+            Err(e) if cached_meta.is_some() => {
+                eprintln!("Failed to revalidate cached tile {}, using cache: {:#?}", url, e);
+                return Ok(Some(read_cache(&body_path).await?));
+            }
+            Err(e) => return Err(anyhow!("Failed to fetch tile data from URL: {}: {:#?}.", url, e)),
+        };
+
+        match res.status() {
+            StatusCode::NOT_MODIFIED if cached_meta.is_some() => Ok(Some(read_cache(&body_path).await?)),
+            StatusCode::NOT_FOUND => {
+                // 削除されたタイルのキャッシュは破棄する
+                let _ = tokio::fs::remove_file(&body_path).await;
+                let _ = tokio::fs::remove_file(&meta_path).await;
+                Ok(None)
+            }
+            status if status.is_success() => {
+                let header = |name| {
+                    res.headers()
+                        .get(name)
+                        .and_then(|v| v.to_str().ok())
+                        .map(str::to_string)
+                };
+                let meta = CacheMeta {
+                    etag: header(ETAG),
+                    last_modified: header(LAST_MODIFIED),
+                };
+
+                let body = res.bytes().await.map_err(|e| {
+                    anyhow!("Failed to read response body from URL: {}: {:#?}", url, e)
+                })?;
+
+                write_atomic(&body_path, &body).await?;
+                write_atomic(&meta_path, meta.serialize().as_bytes()).await?;
+
+                Ok(Some(body.to_vec()))
+            }
+            status => Err(anyhow!("Unexpected status {} from URL: {}", status, url)),
+        }
+    }
+
+    /// キャッシュを使わずに取得する
+    async fn fetch_remote(&self, url: &str) -> anyhow::Result<Option<Vec<u8>>> {
+        let res = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .map_err(|e| anyhow!("Failed to fetch tile data from URL: {}: {:#?}.", url, e))?;
+
+        match res.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            status if status.is_success() => {
+                let body = res.bytes().await.map_err(|e| {
+                    anyhow!("Failed to read response body from URL: {}: {:#?}", url, e)
+                })?;
+                Ok(Some(body.to_vec()))
+            }
+            status => Err(anyhow!("Unexpected status {} from URL: {}", status, url)),
+        }
+    }
+}
+
+/// ローカルのファイルとして読み込むURLであれば、そのパスを返す
+/// ex) file:///mirror/river/18/1/2.geojson, /mirror/river/18/1/2.geojson, ./mirror/river/18/1/2.geojson
+fn local_path(url: &str) -> Option<PathBuf> {
+    if let Some(path) = url.strip_prefix("file://") {
+        Some(PathBuf::from(path))
+    } else if url.starts_with("http://") || url.starts_with("https://") {
+        None
+    } else {
+        Some(PathBuf::from(url))
+    }
+}
+
+/// ローカルのファイルを読み込む
+/// ファイルが存在しない場合は`None`を返す
+async fn read_local(path: &Path) -> anyhow::Result<Option<Vec<u8>>> {
+    match tokio::fs::read(path).await {
+        Ok(body) => Ok(Some(body)),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!("Failed to read tile at {:?}: {:#?}", path, e)),
+    }
+}
+
+/// URLからキャッシュファイルのパスを作る
+/// ex) https://example.com/xyz/18/1/2.geojson -> {cache_dir}/example.com/xyz/18/1/2.geojson
+fn cache_path(cache_dir: &Path, url: &str) -> PathBuf {
+    let without_scheme = url.split_once("://").map(|(_, rest)| rest).unwrap_or(url);
+
+    without_scheme
+        .split('/')
+        .filter(|segment| !segment.is_empty() && *segment != "." && *segment != "..")
+        .map(|segment| {
+            segment
+                .chars()
+                .map(|c| if c.is_ascii_alphanumeric() || "._-".contains(c) { c } else { '_' })
+                .collect::<String>()
+        })
+        .fold(cache_dir.to_path_buf(), |path, segment| path.join(segment))
+}
+
+/// ファイル名の末尾に拡張子を追加したパス
+fn sibling_path(path: &Path, ext: &str) -> PathBuf {
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!("{file_name}.{ext}"))
+}
+
+async fn read_cache(path: &Path) -> anyhow::Result<Vec<u8>> {
+    tokio::fs::read(path)
+        .await
+        .map_err(|e| anyhow!("Failed to read cached tile at {:?}: {:#?}", path, e))
+}
+
+/// 書き込み途中のファイルが残らないよう、一時ファイルに書き込んでから置き換える
+pub async fn write_atomic(path: &Path, body: &[u8]) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        tokio::fs::create_dir_all(parent)
+            .await
+            .map_err(|e| anyhow!("Failed to create cache directory {:?}: {:#?}", parent, e))?;
+    }
+
+    let tmp_path = sibling_path(path, "tmp");
+    tokio::fs::write(&tmp_path, body)
+        .await
+        .map_err(|e| anyhow!("Failed to write cache file {:?}: {:#?}", tmp_path, e))?;
+    tokio::fs::rename(&tmp_path, path)
+        .await
+        .map_err(|e| anyhow!("Failed to write cache file {:?}: {:#?}", path, e))?;
+
+    Ok(())
+}
@@ -0,0 +1,358 @@
+use std::fs::File;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::anyhow;
+use coordinate_transformer::{jpr2ll, ll2jpr, JprOrigin};
+use moka::future::Cache;
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
+use tiff::decoder::{Decoder, DecodingResult, Limits};
+use tiff::tags::Tag;
+
+use crate::dem::{interpolate, Interpolation};
+
+/// ラスタを探すための格子の大きさ[度]
+const GRID_SIZE: f64 = 0.01;
+
+/// GeoKeyDirectoryTagのキー
+const GT_RASTER_TYPE_GEO_KEY: u16 = 1025;
+const GEOGRAPHIC_TYPE_GEO_KEY: u16 = 2048;
+const PROJECTED_CS_TYPE_GEO_KEY: u16 = 3072;
+
+/// GTRasterTypeGeoKeyのRasterPixelIsPoint
+const RASTER_PIXEL_IS_POINT: u16 = 2;
+
+/// GeoTIFFの座標参照系
+#[derive(Debug, Clone, Copy)]
+enum Crs {
+    /// 緯度経度 (EPSG:4326, 4612, 6668)
+    Geographic,
+    /// 日本測地系2011の平面直角座標系 (EPSG:6669-6687)
+    Jpr(JprOrigin),
+}
+
+impl Crs {
+    fn from_epsg(code: u16) -> Option<Self> {
+        match code {
+            4326 | 4612 | 6668 => Some(Crs::Geographic),
+            6669..=6687 => JprOrigin::parse(code - 6668).ok().map(Crs::Jpr),
+            _ => None,
+        }
+    }
+
+    /// 緯度経度(度)からモデル座標(東方向, 北方向)に変換する
+    fn project(self, long: f64, lat: f64) -> (f64, f64) {
+        match self {
+            Crs::Geographic => (long, lat),
+            // 平面直角座標系ではY軸が東方向、X軸が北方向
+            Crs::Jpr(origin) => ll2jpr((long.to_radians(), lat.to_radians()), origin),
+        }
+    }
+
+    /// モデル座標(東方向, 北方向)から緯度経度(度)に変換する
+    fn unproject(self, east: f64, north: f64) -> (f64, f64) {
+        match self {
+            Crs::Geographic => (east, north),
+            Crs::Jpr(origin) => {
+                let (long, lat) = jpr2ll((east, north), origin);
+                (long.to_degrees(), lat.to_degrees())
+            }
+        }
+    }
+}
+
+/// GeoTIFFの位置情報
+#[derive(Debug)]
+struct Raster {
+    path: PathBuf,
+    crs: Crs,
+    width: u32,
+    height: u32,
+    /// 左上の画素の角のモデル座標
+    origin: (f64, f64),
+    /// 1画素の大きさ
+    scale: (f64, f64),
+    nodata: Option<f64>,
+}
+
+impl Raster {
+    /// ヘッダーから位置情報を読み込む
+    fn read(path: &Path) -> anyhow::Result<Self> {
+        let mut decoder = Decoder::new(BufReader::new(File::open(path)?))?;
+        let (width, height) = decoder.dimensions()?;
+
+        let geo_keys = decoder
+            .find_tag_unsigned_vec::<u16>(Tag::GeoKeyDirectoryTag)?
+            .ok_or_else(|| anyhow!("GeoKeyDirectoryTag is missing"))?;
+        // 先頭の4つはヘッダーで、以降は(キー, 格納先のタグ, 個数, 値)の組
+        let geo_key = |key: u16| {
+            geo_keys
+                .get(4..)
+                .unwrap_or_default()
+                .chunks_exact(4)
+                .find(|entry| entry[0] == key && entry[1] == 0)
+                .map(|entry| entry[3])
+        };
+        let crs = geo_key(PROJECTED_CS_TYPE_GEO_KEY)
+            .or_else(|| geo_key(GEOGRAPHIC_TYPE_GEO_KEY))
+            .ok_or_else(|| anyhow!("EPSG code is missing"))?;
+        let crs = Crs::from_epsg(crs).ok_or_else(|| anyhow!("Unsupported CRS: EPSG:{}", crs))?;
+
+        let scale = decoder.get_tag_f64_vec(Tag::ModelPixelScaleTag)?;
+        let tiepoint = decoder.get_tag_f64_vec(Tag::ModelTiepointTag)?;
+        if scale.len() < 2 || tiepoint.len() < 6 {
+            return Err(anyhow!("Invalid ModelPixelScaleTag or ModelTiepointTag"));
+        }
+        // タイポイントは(画素のI, J, K, モデルのX, Y, Z)
+        let (mut i, mut j) = (tiepoint[0], tiepoint[1]);
+        if geo_key(GT_RASTER_TYPE_GEO_KEY) == Some(RASTER_PIXEL_IS_POINT) {
+            // 画素の値が画素の中心を表すよう、タイポイントを画素の角に合わせる
+            (i, j) = (i + 0.5, j + 0.5);
+        }
+        let origin = (tiepoint[3] - i * scale[0], tiepoint[4] + j * scale[1]);
+
+        let nodata = match decoder.find_tag(Tag::GdalNodata)? {
+            Some(_) => Some(
+                decoder
+                    .get_tag_ascii_string(Tag::GdalNodata)?
+                    .trim_matches(char::from(0))
+                    .trim()
+                    .parse::<f64>()?,
+            ),
+            None => None,
+        };
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            crs,
+            width,
+            height,
+            origin,
+            scale: (scale[0], scale[1]),
+            nodata,
+        })
+    }
+
+    /// 緯度経度(度)を小数を含む画素の座標に変換する (ラスタの範囲外では`None`)
+    fn pixel_position(&self, long: f64, lat: f64) -> Option<(f64, f64)> {
+        let (east, north) = self.crs.project(long, lat);
+        let x = (east - self.origin.0) / self.scale.0;
+        let y = (self.origin.1 - north) / self.scale.1;
+
+        ((0. ..=self.width as f64).contains(&x) && (0. ..=self.height as f64).contains(&y)).then_some((x, y))
+    }
+
+    /// 緯度経度の範囲 (最小経度, 最小緯度, 最大経度, 最大緯度)
+    fn bounds(&self) -> (f64, f64, f64, f64) {
+        let (width, height) = (
+            self.width as f64 * self.scale.0,
+            self.height as f64 * self.scale.1,
+        );
+        // 平面直角座標系では範囲の辺が経線・緯線と平行にならないため、4隅から求める
+        [(0., 0.), (width, 0.), (0., -height), (width, -height)]
+            .iter()
+            .map(|(dx, dy)| self.crs.unproject(self.origin.0 + dx, self.origin.1 + dy))
+            .fold(
+                (f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NEG_INFINITY),
+                |(min_long, min_lat, max_long, max_lat), (long, lat)| {
+                    (min_long.min(long), min_lat.min(lat), max_long.max(long), max_lat.max(lat))
+                },
+            )
+    }
+
+    /// 画素の値を読み込む (欠損値は`NaN`)
+    fn read_values(&self) -> anyhow::Result<Vec<f32>> {
+        let mut decoder =
+            Decoder::new(BufReader::new(File::open(&self.path)?))?.with_limits(Limits::unlimited());
+        let samples = decoder.find_tag_unsigned::<usize>(Tag::SamplesPerPixel)?.unwrap_or(1);
+
+        let values: Vec<f32> = match decoder.read_image()? {
+            DecodingResult::U8(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::U16(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::U32(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::U64(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::F32(v) => v,
+            DecodingResult::F64(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I8(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I16(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I32(v) => v.into_iter().map(|v| v as f32).collect(),
+            DecodingResult::I64(v) => v.into_iter().map(|v| v as f32).collect(),
+        };
+
+        // 複数のバンドを持つ場合は最初のバンドを標高とする
+        let nodata = self.nodata.map(|nodata| nodata as f32);
+        Ok(values
+            .into_iter()
+            .step_by(samples.max(1))
+            .map(|value| if Some(value) == nodata { f32::NAN } else { value })
+            .collect())
+    }
+}
+
+/// ローカルのGeoTIFFから標高を求める
+pub(crate) struct GeoTiffDem {
+    rasters: Vec<Raster>,
+    /// 格子ごとの、範囲が重なるラスタの番号
+    grid: FxHashMap<(i64, i64), Vec<usize>>,
+    /// ラスタの番号ごとの画素の値 (読み込めなかった場合は`None`)
+    cache: Cache<usize, Option<Arc<Vec<f32>>>, FxBuildHasher>,
+}
+
+impl GeoTiffDem {
+    /// ディレクトリ(サブディレクトリを含む)内の*.tif, *.tiff、またはGDALのVRTファイルが参照するGeoTIFFを開く
+    /// 複数のラスタが重なる場合は、パスの順で先のものを優先する
+    pub fn open(path: &str) -> Self {
+        let path = Path::new(path);
+        let mut paths = if path.is_dir() {
+            find_tiff_files(path)
+        } else {
+            read_vrt_sources(path)
+        };
+        paths.sort();
+
+        let rasters = paths
+            .iter()
+            .filter_map(|path| match Raster::read(path) {
+                Ok(raster) => Some(raster),
+                Err(e) => {
+                    eprintln!("Skipping GeoTIFF {:?}: {:#}", path, e);
+                    None
+                }
+            })
+            .collect::<Vec<_>>();
+        if rasters.is_empty() {
+            panic!("No usable GeoTIFF found in {:?}", path);
+        }
+
+        let mut grid = FxHashMap::<_, Vec<_>>::default();
+        for (index, raster) in rasters.iter().enumerate() {
+            let (min_long, min_lat, max_long, max_lat) = raster.bounds();
+            let (min_x, min_y) = grid_cell(min_long, min_lat);
+            let (max_x, max_y) = grid_cell(max_long, max_lat);
+            for x in min_x..=max_x {
+                for y in min_y..=max_y {
+                    grid.entry((x, y)).or_default().push(index);
+                }
+            }
+        }
+
+        // 1mのDEMでは1枚あたり数十MBになるため、少数のみをキャッシュする
+        let cache = Cache::builder()
+            .max_capacity(16)
+            .build_with_hasher(FxBuildHasher);
+
+        Self {
+            rasters,
+            grid,
+            cache,
+        }
+    }
+
+    /// 地点の標高[m]
+    /// 地点を含むラスタが無い場合や、欠損値の画素では`None`を返す
+    pub async fn sample(&self, long: f64, lat: f64, interpolation: Interpolation) -> Option<f32> {
+        let candidates = self.grid.get(&grid_cell(long, lat))?;
+
+        for &index in candidates {
+            let raster = &self.rasters[index];
+            let Some((x, y)) = raster.pixel_position(long, lat) else {
+                continue;
+            };
+            let Some(values) = self.values(index).await else {
+                continue;
+            };
+
+            let (width, height) = (raster.width as i64, raster.height as i64);
+            let altitude = interpolate(interpolation, x, y, |x, y| {
+                if !(0..width).contains(&x) || !(0..height).contains(&y) {
+                    return None;
+                }
+                let value = values[(y * width + x) as usize];
+                (!value.is_nan()).then_some(value)
+            });
+            if altitude.is_some() {
+                return altitude;
+            }
+        }
+
+        None
+    }
+
+    /// ラスタの画素の値を読み込む
+    async fn values(&self, index: usize) -> Option<Arc<Vec<f32>>> {
+        self.cache
+            .get_with(index, async {
+                let raster = &self.rasters[index];
+                // 展開に時間がかかるため、非同期のランタイムを止めないようにする
+                let result = tokio::task::block_in_place(|| raster.read_values());
+                match result {
+                    Ok(values) if values.len() == raster.width as usize * raster.height as usize => {
+                        Some(Arc::new(values))
+                    }
+                    Ok(_) => {
+                        eprintln!("Failed to read GeoTIFF {:?}: unexpected number of pixels", raster.path);
+                        None
+                    }
+                    Err(e) => {
+                        eprintln!("Failed to read GeoTIFF {:?}: {:#}", raster.path, e);
+                        None
+                    }
+                }
+            })
+            .await
+    }
+}
+
+/// 緯度経度(度)を含む格子
+fn grid_cell(long: f64, lat: f64) -> (i64, i64) {
+    ((long / GRID_SIZE).floor() as i64, (lat / GRID_SIZE).floor() as i64)
+}
+
+/// ディレクトリ内のGeoTIFFファイルを再帰的に探す
+fn find_tiff_files(dir: &Path) -> Vec<PathBuf> {
+    let entries = std::fs::read_dir(dir)
+        .unwrap_or_else(|e| panic!("Failed to read directory {:?}: {:#?}", dir, e));
+
+    entries
+        .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+        .flat_map(|path| {
+            if path.is_dir() {
+                find_tiff_files(&path)
+            } else {
+                let is_tiff = path
+                    .extension()
+                    .and_then(|ext| ext.to_str())
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("tif") || ext.eq_ignore_ascii_case("tiff"));
+                is_tiff.then_some(path).into_iter().collect()
+            }
+        })
+        .collect()
+}
+
+/// GDALのVRTファイルから参照しているGeoTIFFのパスを読み込む
+/// 配置はVRTではなく各GeoTIFFの位置情報に従う
+fn read_vrt_sources(path: &Path) -> Vec<PathBuf> {
+    let vrt = std::fs::read_to_string(path)
+        .unwrap_or_else(|e| panic!("Failed to read VRT file {:?}: {:#?}", path, e));
+    let base_dir = path.parent().unwrap_or(Path::new("."));
+
+    // ex) <SourceFilename relativeToVRT="1">tiles/a.tif</SourceFilename>
+    vrt.split("<SourceFilename")
+        .skip(1)
+        .filter_map(|element| {
+            let (attributes, rest) = element.split_once('>')?;
+            let (filename, _) = rest.split_once("</SourceFilename>")?;
+            let filename = filename.trim().replace("&amp;", "&");
+            let relative = attributes.contains("relativeToVRT=\"1\"");
+
+            Some(if relative {
+                base_dir.join(filename)
+            } else {
+                PathBuf::from(filename)
+            })
+        })
+        .collect::<FxHashSet<_>>()
+        .into_iter()
+        .collect()
+}
@@ -0,0 +1,331 @@
+use std::collections::VecDeque;
+use std::path::Path;
+
+use csv::{ReaderBuilder, WriterBuilder};
+use rustc_hash::FxHashMap;
+
+use crate::collect::{LinkGeometry, LINK_GEOMETRY_COLUMNS};
+use crate::stitch::parse_location;
+
+/// ヘッダーの列名で値を読み書きするCSVファイル
+/// river_node.csvやriver_link.csvのように、後処理で列が追加されるファイルを扱う
+pub struct Table {
+    pub header: Vec<String>,
+    pub rows: Vec<Vec<String>>,
+}
+
+impl Table {
+    pub fn read(path: &Path) -> Self {
+        let mut reader = ReaderBuilder::new()
+            .has_headers(true)
+            .flexible(true)
+            .from_path(path)
+            .unwrap_or_else(|e| panic!("Failed to open {:?}: {:#?}", path, e));
+
+        let header = reader
+            .headers()
+            .unwrap_or_else(|e| panic!("Failed to read header of {:?}: {:#?}", path, e))
+            .iter()
+            .map(str::to_string)
+            .collect::<Vec<_>>();
+        let rows = reader
+            .records()
+            .map(|record| {
+                let record = record.unwrap_or_else(|e| panic!("Failed to read {:?}: {:#?}", path, e));
+                let mut row = record.iter().map(str::to_string).collect::<Vec<_>>();
+                row.resize(header.len(), String::new());
+                row
+            })
+            .collect();
+
+        Self { header, rows }
+    }
+
+    pub fn write(&self, path: &Path) {
+        let mut writer = WriterBuilder::new()
+            .from_path(path)
+            .unwrap_or_else(|e| panic!("Failed to create {:?}: {:#?}", path, e));
+
+        for row in std::iter::once(&self.header).chain(&self.rows) {
+            writer
+                .write_record(row)
+                .unwrap_or_else(|e| panic!("Failed to write {:?}: {:#?}", path, e));
+        }
+        writer
+            .flush()
+            .unwrap_or_else(|e| panic!("Failed to flush {:?}: {:#?}", path, e));
+    }
+
+    /// 列名が一致する列の位置
+    pub fn column(&self, name: &str) -> Option<usize> {
+        self.header.iter().position(|column| column == name)
+    }
+
+    /// 列名が条件を満たす最初の列の位置
+    pub fn column_by(&self, predicate: impl Fn(&str) -> bool) -> Option<usize> {
+        self.header.iter().position(|column| predicate(column))
+    }
+
+    /// 必須の列の位置
+    pub fn expect_column(&self, name: &str) -> usize {
+        self.column(name)
+            .unwrap_or_else(|| panic!("Column {:?} not found in {:?}", name, self.header))
+    }
+
+    /// 列の値を置き換える (列が無い場合は末尾に追加する)
+    pub fn set_column(&mut self, name: &str, values: impl IntoIterator<Item = String>) {
+        let column = match self.column(name) {
+            Some(column) => column,
+            None => {
+                self.header.push(name.to_string());
+                self.rows.iter_mut().for_each(|row| row.push(String::new()));
+                self.header.len() - 1
+            }
+        };
+
+        for (row, value) in self.rows.iter_mut().zip(values) {
+            row[column] = value;
+        }
+    }
+}
+
+/// ノードとリンクのCSVから組み立てた河川ネットワーク
+/// ノードとリンクはファイルの行とは別の連番で参照する
+pub struct RiverGraph {
+    /// ノードID
+    pub ids: Vec<usize>,
+    /// ノードIDから連番への対応
+    pub index: FxHashMap<usize, usize>,
+    /// ノードの(経度, 緯度)
+    pub coords: Vec<(f64, f64)>,
+    /// ノードの標高 (標高が得られなかったノードは`None`)
+    pub altitudes: Vec<Option<f32>>,
+    /// ノードの行番号
+    pub node_rows: Vec<usize>,
+    pub links: Vec<GraphLink>,
+    /// ノードに接続するリンクの番号
+    pub adjacency: Vec<Vec<usize>>,
+}
+
+/// ネットワークのリンク
+#[derive(Debug, Clone, Copy)]
+pub struct GraphLink {
+    /// 始点ノードの連番
+    pub start: usize,
+    /// 終点ノードの連番
+    pub end: usize,
+    /// リンクの長さ[m]
+    pub length: f64,
+    /// リンクの行番号
+    pub row: usize,
+}
+
+impl GraphLink {
+    /// リンクの反対側のノード
+    pub fn other(&self, node: usize) -> usize {
+        if self.start == node {
+            self.end
+        } else {
+            self.start
+        }
+    }
+}
+
+/// river_node.csvのノードIDの列
+pub fn find_node_id_column(nodes: &Table) -> usize {
+    nodes
+        .column_by(|column| column.ends_with(":ID"))
+        .unwrap_or_else(|| panic!("Node ID column not found in {:?}", nodes.header))
+}
+
+/// BoundNodeなど、河川のノードでない行か
+pub fn is_river_node(nodes: &Table, row: &[String]) -> bool {
+    nodes
+        .column(":LABEL")
+        .is_none_or(|column| row[column] == "RiverNode")
+}
+
+/// リンクが`network direction`で向き付けられているかを確認する
+pub fn require_directed(links: &Table) {
+    let type_column = links.expect_column(":TYPE");
+    if links.rows.iter().any(|row| row[type_column] != "FLOWS_TO") {
+        panic!("Links are not directed. Run `network direction` first.");
+    }
+}
+
+pub fn parse_node_id(s: &str) -> usize {
+    s.parse()
+        .unwrap_or_else(|_| panic!("Failed to parse node ID: {:?}", s))
+}
+
+impl RiverGraph {
+    pub fn new(nodes: &Table, links: &Table) -> Self {
+        let id_column = find_node_id_column(nodes);
+        let location_column = nodes
+            .column_by(|column| column.starts_with("location"))
+            .unwrap_or_else(|| panic!("Location column not found in {:?}", nodes.header));
+        let altitude_column = nodes.column("altitude:float");
+
+        let mut graph = Self {
+            ids: Vec::new(),
+            index: FxHashMap::default(),
+            coords: Vec::new(),
+            altitudes: Vec::new(),
+            node_rows: Vec::new(),
+            links: Vec::new(),
+            adjacency: Vec::new(),
+        };
+
+        for (row_number, row) in nodes.rows.iter().enumerate() {
+            if !is_river_node(nodes, row) {
+                continue;
+            }
+            let id = parse_node_id(&row[id_column]);
+            if graph.index.contains_key(&id) {
+                continue;
+            }
+            let location = parse_location(&row[location_column])
+                .unwrap_or_else(|| panic!("Failed to parse location: {:?}", row[location_column]));
+
+            graph.index.insert(id, graph.ids.len());
+            graph.ids.push(id);
+            graph.coords.push(location);
+            graph.altitudes.push(altitude_column.and_then(|column| row[column].parse().ok()));
+            graph.node_rows.push(row_number);
+            graph.adjacency.push(Vec::new());
+        }
+
+        let start_column = links.expect_column(":START_ID");
+        let end_column = links.expect_column(":END_ID");
+        let length_column = links.column("length:float");
+        for (row_number, row) in links.rows.iter().enumerate() {
+            let node = |column: usize| {
+                let id = parse_node_id(&row[column]);
+                *graph
+                    .index
+                    .get(&id)
+                    .unwrap_or_else(|| panic!("Link refers to unknown node {}", id))
+            };
+            let link = GraphLink {
+                start: node(start_column),
+                end: node(end_column),
+                length: length_column
+                    .and_then(|column| row[column].parse().ok())
+                    .unwrap_or_default(),
+                row: row_number,
+            };
+
+            let link_index = graph.links.len();
+            graph.adjacency[link.start].push(link_index);
+            if link.end != link.start {
+                graph.adjacency[link.end].push(link_index);
+            }
+            graph.links.push(link);
+        }
+
+        graph
+    }
+
+    pub fn node_count(&self) -> usize {
+        self.ids.len()
+    }
+
+    /// ノードの次数
+    pub fn degree(&self, node: usize) -> usize {
+        self.adjacency[node].len()
+    }
+
+    /// ノードに流れ込むリンクの番号
+    pub fn incoming(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        self.adjacency[node]
+            .iter()
+            .copied()
+            .filter(move |link| self.links[*link].end == node)
+    }
+
+    /// ノードから流れ出るリンクの番号
+    pub fn outgoing(&self, node: usize) -> impl Iterator<Item = usize> + '_ {
+        self.adjacency[node]
+            .iter()
+            .copied()
+            .filter(move |link| self.links[*link].start == node)
+    }
+
+    /// 連結成分ごとの番号を振る
+    /// 戻り値は(ノードごとの成分番号, 成分の数)
+    pub fn components(&self) -> (Vec<usize>, usize) {
+        let mut component = vec![usize::MAX; self.node_count()];
+        let mut count = 0;
+        let mut stack = Vec::new();
+
+        for root in 0..self.node_count() {
+            if component[root] != usize::MAX {
+                continue;
+            }
+            component[root] = count;
+            stack.push(root);
+            while let Some(node) = stack.pop() {
+                for &link in &self.adjacency[node] {
+                    let other = self.links[link].other(node);
+                    if component[other] == usize::MAX {
+                        component[other] = count;
+                        stack.push(other);
+                    }
+                }
+            }
+            count += 1;
+        }
+
+        (component, count)
+    }
+
+    /// FLOWS_TOリンクに沿って上流から下流の順に並べたノード
+    /// 循環に含まれるノードと、その下流のノードは含まない
+    pub fn topological_order(&self) -> Vec<usize> {
+        let mut in_degree = (0..self.node_count())
+            .map(|node| self.incoming(node).count())
+            .collect::<Vec<_>>();
+        let mut queue = (0..self.node_count())
+            .filter(|node| in_degree[*node] == 0)
+            .collect::<VecDeque<_>>();
+        let mut order = Vec::with_capacity(self.node_count());
+
+        while let Some(node) = queue.pop_front() {
+            order.push(node);
+            for link in self.outgoing(node) {
+                let end = self.links[link].end;
+                in_degree[end] -= 1;
+                if in_degree[end] == 0 {
+                    queue.push_back(end);
+                }
+            }
+        }
+
+        order
+    }
+
+    /// リンクの形状 (`reversed`の場合は終点から始点への向き)
+    pub fn link_geometry(&self, link: usize, reversed: bool) -> LinkGeometry {
+        let GraphLink {
+            start, end, length, ..
+        } = self.links[link];
+        let (start, end) = if reversed { (end, start) } else { (start, end) };
+        let node = |node: usize| (self.coords[node].0, self.coords[node].1, self.altitudes[node]);
+
+        LinkGeometry::new(node(start), node(end), length)
+    }
+
+    /// リンクの標高差・勾配・3次元の長さ・方位角の列を書き直す (長さの列はそのまま残す)
+    /// `reversed`はリンクごとに、行の始点と終点がグラフと逆になっているか
+    pub fn write_link_geometry(&self, links: &mut Table, reversed: &[bool]) {
+        let mut columns = vec![vec![String::new(); links.rows.len()]; LINK_GEOMETRY_COLUMNS.len()];
+        for (i, link) in self.links.iter().enumerate() {
+            for (column, value) in columns.iter_mut().zip(self.link_geometry(i, reversed[i]).columns()) {
+                column[link.row] = value;
+            }
+        }
+        for (name, column) in LINK_GEOMETRY_COLUMNS.iter().zip(columns).skip(1) {
+            links.set_column(name, column);
+        }
+    }
+}
@@ -1,9 +1,34 @@
-use crate::collect::collect_river_data;
+use crate::basin::label_basins;
+use crate::burn::{burn_altitudes, BurnMethod};
+use crate::collect::{collect_river_data, SelectionMode};
+use crate::dem::{DemFormat, Interpolation};
+use crate::direction::infer_direction;
+use crate::distance::{DistanceModel, Ellipsoid};
+use crate::mirror::mirror_tiles;
+use crate::order::compute_stream_order;
+use crate::reach::build_reaches;
+use crate::tilelocate::MembershipMode;
+use crate::update::update_river_data;
 use clap::{Parser, Subcommand};
-use coordinate_transformer::ZoomLv;
 
+mod aoi;
+mod basin;
+mod burn;
+mod checkpoint;
 mod collect;
+mod dem;
+mod direction;
+mod distance;
+mod fetch;
+mod geotiff;
+mod graph;
+mod mirror;
+mod mokuroku;
+mod order;
+mod reach;
+mod stitch;
 mod tilelocate;
+mod update;
 
 /// メインコマンドの構造体
 #[derive(Parser, Debug)]
@@ -21,22 +46,144 @@ enum Commands {
     /// 河川データを収集し、書き出す
     Collect(CollectArgs),
     ///ドロネー三角分割を行った際のノード間のつながりを書き出す
-    Tilelocate {
-        /// 河川データのriver_node.csvのパス
-        #[arg(short, long)]
-        input: String,
-        #[arg(short, long, default_value = "15")]
-        max_zoomlv: u8,
+    Tilelocate(TilelocateArgs),
+    /// 河川データとDEMデータのタイルをローカルのディレクトリに保存する
+    Mirror(MirrorArgs),
+    /// 収集した河川ネットワークの後処理を行う
+    Network {
+        #[command(subcommand)]
+        command: NetworkCommands,
     },
 }
 
+/// `network` サブコマンドの種類
+#[derive(Subcommand, Debug)]
+enum NetworkCommands {
+    /// 標高とネットワークの形状からリンクの流下方向を推定し、FLOWS_TOに書き換える
+    Direction(DirectionArgs),
+    /// FLOWS_TOリンクのストレーラー次数、シュリーブ次数、ホートン次数を求める
+    Order(NetworkArgs),
+    /// 次数2のノードの連なりをREACHリンクにまとめ、river_junction.csvとriver_reach.csvに書き出す
+    Reach(NetworkArgs),
+    /// 繋がっているノードとリンクに水系ごとのbasin_idを付与し、水系の集計をriver_basin.csvに書き出す
+    Basin(NetworkArgs),
+    /// FLOWS_TOリンクに沿って下流の標高が上流を超えないようにノードの標高を補正する
+    Burn(BurnArgs),
+}
+
+/// `tilelocate` サブコマンドの引数を定義する構造体
+#[derive(Parser, Debug)]
+struct TilelocateArgs {
+    /// 河川データのriver_node.csvのパス
+    #[arg(short, long)]
+    input: String,
+
+    /// 河川データのriver_link.csvのパス (省略時は--inputと同じディレクトリのriver_link.csv)
+    #[arg(short, long)]
+    links: Option<String>,
+
+    #[arg(short, long, default_value = "15")]
+    max_zoomlv: u8,
+
+    /// ノードをタイルに所属させる方法
+    /// nodeはノードが含まれるタイル、triangleはノードを頂点とするドロネー三角形と重なるタイル、linkはノードを端点とするドロネー三角分割の辺が通るタイル、
+    /// riverはノードを端点とするRIVER_LINKが通るタイル
+    #[arg(long, value_enum, default_value = "triangle")]
+    membership: MembershipMode,
+
+    /// RIVER_LINKが通るタイルを、始点のノードからタイルへのCROSSESリレーションシップとしてriver_tile_crossing.csvに書き出す
+    /// リンクの終点のノードIDは`link_end_id:long`に書き出す
+    #[arg(long)]
+    crosses: bool,
+
+    /// 同じ入力から常に同じ出力が得られるよう、タイルを(z, x, y)順、所属をノードID順、NEARリンクを(始点, 終点)順に並べ替えて書き出す
+    #[arg(long)]
+    deterministic: bool,
+
+    /// ドロネー三角分割の辺をNEARリンクとしてdelaunay_link.csvに書き出す
+    #[arg(long)]
+    delaunay_links: bool,
+
+    /// 書き出すNEARリンクの最大の長さ[m]
+    #[arg(long, requires = "delaunay_links")]
+    near_max_length: Option<f64>,
+
+    /// 異なる水系のノードを結ぶNEARリンクのみを書き出す
+    /// 水系はノードの`basin_id:long` (network basinで付与)、無い場合はriver_link.csvで繋がったノードとする
+    #[arg(long, requires = "delaunay_links")]
+    near_different_rivers: bool,
+
+    #[command(flatten)]
+    distance: DistanceArgs,
+}
+
+/// リンクの長さの求め方
+#[derive(Parser, Debug)]
+struct DistanceArgs {
+    /// リンクの長さの求め方
+    /// haversineは球面上の距離、vincentyとkarneyは--ellipsoidの楕円体上の測地線長、planeはJGD2011の平面直角座標系での距離
+    #[arg(long, value_enum, default_value = "haversine")]
+    distance: DistanceModel,
+
+    /// --distanceがvincentyまたはkarneyの場合に使う楕円体
+    #[arg(long, value_enum, default_value = "grs80")]
+    ellipsoid: Ellipsoid,
+
+    /// --distanceがplaneの場合に使う平面直角座標系の系番号 (planeでは必須)
+    /// 全てのリンクをこの系で測るため、範囲は系の適用区域に収まるようにする
+    #[arg(long, value_parser = clap::value_parser!(u8).range(1..=19), required_if_eq("distance", "plane"))]
+    plane_zone: Option<u8>,
+}
+
+/// `network` サブコマンドで共通の入力ファイル
+#[derive(Parser, Debug)]
+struct NetworkArgs {
+    /// 河川データのriver_node.csvのパス
+    #[arg(short, long, default_value = "./river_node.csv")]
+    nodes: String,
+
+    /// 河川データのriver_link.csvのパス (結果で上書きする)
+    #[arg(short, long, default_value = "./river_link.csv")]
+    links: String,
+}
+
+/// `network direction` サブコマンドの引数を定義する構造体
+#[derive(Parser, Debug)]
+struct DirectionArgs {
+    #[command(flatten)]
+    network: NetworkArgs,
+
+    /// 末端のノードを河口とみなす標高の上限[m]
+    #[arg(long, default_value_t = 1.0)]
+    mouth_altitude: f32,
+
+    /// 下流側のノードが上流側よりこの値[m]を超えて高い場合、方向が疑わしいリンクとする
+    #[arg(long, default_value_t = 10.0)]
+    max_rise: f32,
+}
+
+/// `network burn` サブコマンドの引数を定義する構造体
+#[derive(Parser, Debug)]
+struct BurnArgs {
+    #[command(flatten)]
+    network: NetworkArgs,
+
+    /// 標高の補正方法
+    #[arg(long, value_enum, default_value = "breach")]
+    method: BurnMethod,
+}
+
 /// `collect` サブコマンドの引数を定義する構造体
 #[derive(Parser, Debug)]
 struct CollectArgs {
-    /// 河川データの目録ファイルのパス
+    /// 河川データの目録ファイルのパス (gzipで圧縮されたmokuroku.csv.gzも指定できる)
     #[arg(short, long, default_value = "./mokuroku.csv")]
     mokuroku: String,
 
+    /// 実行前に`{river_base_url}mokuroku.csv.gz`を取得し、`--mokuroku`のパスに保存する
+    #[arg(long)]
+    fetch_mokuroku: bool,
+
     /// 処理のバッチサイズ
     #[arg(short, long, default_value_t = 100)]
     batch: usize,
@@ -50,6 +197,112 @@ struct CollectArgs {
     #[arg(short, long, default_value = "all")]
     category: String,
 
+    /// リンクに書き出すフィーチャのプロパティのキー (コンマ区切り)
+    /// 中心線の種別(type)とカテゴリ(rivCtg)は常にrvrcl_type:string, riv_ctg:stringとして書き出される
+    #[arg(long, default_value = "name,rID")]
+    link_property: String,
+
+    /// 河川データのベースURL (`file://`またはディレクトリのパスを指定するとローカルから読み込む)
+    #[arg(short, long, default_value = "https://cyberjapandata.gsi.go.jp/xyz/experimental_rvrcl/")]
+    river_base_url: String,
+
+    /// DEMデータのベースURL (`file://`またはディレクトリのパスを指定するとローカルから読み込む)
+    #[arg(short, long, default_value = "https://tiles.gsj.jp/tiles/elev/land/")]
+    dem_base_url: String,
+
+    /// DEMタイルのURLのテンプレート (`{z}`, `{x}`, `{y}`をタイル座標に置き換える)
+    /// 省略時は--dem-base-urlに--dem-formatごとの既定のパス(gsjでは`{z}/{y}/{x}.png`)を続ける
+    #[arg(long)]
+    dem_url: Option<String>,
+
+    /// DEMタイルの形式
+    #[arg(long, value_enum, default_value = "gsj")]
+    dem_format: DemFormat,
+
+    /// 標高を検索する際に参照するDEMデータのズームレベル
+    #[arg(short, long, default_value_t = 14)]
+    zoom_lv: u8,
+
+    /// DEMタイルが存在しない場合に遡る最小のズームレベル (--zoom-lvと同じ値で遡らない)
+    #[arg(long, default_value_t = 10)]
+    dem_min_zoom: u8,
+
+    /// 標高を求めるローカルのGeoTIFF(平面直角座標系・緯度経度)のディレクトリ、またはGDALのVRTファイル
+    /// GeoTIFFの範囲外や欠損値の地点ではDEMタイルから標高を求める
+    #[arg(long)]
+    dem_geotiff: Option<String>,
+
+    /// 標高を求めるDEMを`FORMAT=LOCATION`で指定する (複数指定でき、先に指定したものを優先する)
+    /// FORMATはgsj, mapbox, terrarium, gsi-text, geotiffのいずれかで、LOCATIONはタイルのURLのテンプレート、またはGeoTIFFのディレクトリかVRTファイル
+    /// 指定した場合は--dem-base-url, --dem-url, --dem-format, --dem-geotiffを使わない
+    /// ex) --dem-source 'gsj=https://tiles.gsj.jp/tiles/elev/land/{z}/{y}/{x}.png' --dem-source 'gsj=https://tiles.gsj.jp/tiles/elev/mixed/{z}/{y}/{x}.png'
+    #[arg(long, conflicts_with_all = ["dem_url", "dem_geotiff"])]
+    dem_source: Vec<String>,
+
+    /// 標高の補間方法
+    /// タイルの境界をまたいで周囲の画素を参照し、欠損値の画素は補間に使わない
+    #[arg(long, value_enum, default_value = "bilinear")]
+    dem_interpolation: Interpolation,
+
+    #[command(flatten)]
+    distance: DistanceArgs,
+
+    /// ノードIDに使うヒルベルト値のズームレベル (18で約0.6m、最大の24で約1cm・64bitの精度になる)
+    /// 同じピクセルに含まれる頂点は1つのノードにまとめられる
+    #[arg(long, default_value_t = 18, value_parser = clap::value_parser!(u8).range(0..=24))]
+    id_zoom: u8,
+
+    /// データを取得する範囲の緯度経度　ex) "134.0,135.0,34.0,35.0"
+    #[arg(short, long, group = "region")]
+    aabb: Option<String>,
+
+    /// データを取得する範囲を表すGeoJSONファイル(Polygon/MultiPolygon)のパス
+    #[arg(long, group = "region")]
+    aoi: Option<String>,
+
+    /// 範囲に対するタイルの選択方法 (省略時は--aabbではcontained、--aoiではclip)
+    /// --aabbまたは--aoiと合わせて指定する
+    #[arg(long, value_enum, requires = "region")]
+    select: Option<SelectionMode>,
+
+    /// 取得したタイルを保存するキャッシュディレクトリ
+    /// 指定した場合、2回目以降はETag/Last-Modifiedで再検証し、変更のあったタイルのみをダウンロードする
+    #[arg(long)]
+    cache_dir: Option<String>,
+
+    /// 中断した処理をチェックポイントから再開する
+    #[arg(long)]
+    resume: bool,
+
+    /// 前回の実行時から変更のあったタイルのみを取得し直し、差分をriver_changeset.csvに書き出す
+    #[arg(long, conflicts_with = "resume")]
+    update: bool,
+
+    /// タイルの境界で分断された中心線の端点を接続する距離の許容値[m] (既定値の0では接続しない)
+    /// 接続できなかった端点はriver_stitch_report.csvに書き出す
+    #[arg(long, default_value_t = 0.0)]
+    stitch_tolerance: f64,
+
+    /// 同じ入力から常に同じ出力が得られるよう、ノードをID順、リンクを(始点, 終点)順に並べ替えて書き出す
+    #[arg(long)]
+    deterministic: bool,
+}
+
+/// `mirror` サブコマンドの引数を定義する構造体
+#[derive(Parser, Debug)]
+struct MirrorArgs {
+    /// 河川データの目録ファイルのパス (gzipで圧縮されたmokuroku.csv.gzも指定できる)
+    #[arg(short, long, default_value = "./mokuroku.csv")]
+    mokuroku: String,
+
+    /// 実行前に`{river_base_url}mokuroku.csv.gz`を取得し、`--mokuroku`のパスに保存する
+    #[arg(long)]
+    fetch_mokuroku: bool,
+
+    /// 同時にダウンロードするタイルの数
+    #[arg(short, long, default_value_t = 100)]
+    batch: usize,
+
     /// 河川データのベースURL
     #[arg(short, long, default_value = "https://cyberjapandata.gsi.go.jp/xyz/experimental_rvrcl/")]
     river_base_url: String,
@@ -58,13 +311,45 @@ struct CollectArgs {
     #[arg(short, long, default_value = "https://tiles.gsj.jp/tiles/elev/land/")]
     dem_base_url: String,
 
-    /// 標高を検索する際に参照するDEMデータのズームレベル
+    /// DEMタイルのURLのテンプレート (`{z}`, `{x}`, `{y}`をタイル座標に置き換える)
+    /// 保存先はテンプレートのタイル座標を含むパスの部分に合わせる
+    #[arg(long)]
+    dem_url: Option<String>,
+
+    /// DEMタイルの形式 (--dem-urlを省略した場合のパスを決める)
+    #[arg(long, value_enum, default_value = "gsj")]
+    dem_format: DemFormat,
+
+    /// 保存するDEMを`FORMAT=LOCATION`で指定する (collectの--dem-sourceと同じ形式で、複数指定できる)
+    /// タイルのDEMは指定した順に`{output}/dem/0/`, `{output}/dem/1/`, ...に保存し、GeoTIFFはローカルのファイルなので保存しない
+    /// 指定した場合は--dem-base-url, --dem-url, --dem-formatを使わない
+    #[arg(long, conflicts_with = "dem_url")]
+    dem_source: Vec<String>,
+
+    /// 保存するDEMデータのズームレベル
     #[arg(short, long, default_value_t = 14)]
     zoom_lv: u8,
 
+    /// DEMタイルが存在しない場合に遡って保存する最小のズームレベル
+    #[arg(long, default_value_t = 10)]
+    dem_min_zoom: u8,
+
     /// データを取得する範囲の緯度経度　ex) "134.0,135.0,34.0,35.0"
-    #[arg(short, long)]
+    #[arg(short, long, group = "region")]
     aabb: Option<String>,
+
+    /// データを取得する範囲を表すGeoJSONファイル(Polygon/MultiPolygon)のパス
+    #[arg(long, group = "region")]
+    aoi: Option<String>,
+
+    /// 範囲に対するタイルの選択方法 (省略時は--aabbではcontained、--aoiではclip)
+    /// --aabbまたは--aoiと合わせて指定する
+    #[arg(long, value_enum, requires = "region")]
+    select: Option<SelectionMode>,
+
+    /// 保存先のディレクトリ
+    #[arg(short, long, default_value = "./mirror")]
+    output: String,
 }
 
 #[tokio::main]
@@ -72,11 +357,17 @@ async fn main() {
     let cli = Cli::parse(); // コマンドライン引数をパース
 
     match &cli.command {
+        Commands::Collect(args) if args.update => update_river_data(args).await, // collect --updateが呼ばれた場合
         Commands::Collect(args) => collect_river_data(args).await, // collectサブコマンドが呼ばれた場合
-        Commands::Tilelocate { input, max_zoomlv } => {
-            let max_zoomlv = ZoomLv::parse(*max_zoomlv).expect("Failed to parse the zoom level");
-            tilelocate::tile_locator(input, max_zoomlv)
-        } // delaunayサブコマンドが呼ばれた場合
+        Commands::Tilelocate(args) => tilelocate::tile_locator(args), // tilelocateサブコマンドが呼ばれた場合
+        Commands::Mirror(args) => mirror_tiles(args).await, // mirrorサブコマンドが呼ばれた場合
+        Commands::Network { command } => match command {
+            NetworkCommands::Direction(args) => infer_direction(args), // network directionサブコマンドが呼ばれた場合
+            NetworkCommands::Order(args) => compute_stream_order(args), // network orderサブコマンドが呼ばれた場合
+            NetworkCommands::Reach(args) => build_reaches(args), // network reachサブコマンドが呼ばれた場合
+            NetworkCommands::Basin(args) => label_basins(args), // network basinサブコマンドが呼ばれた場合
+            NetworkCommands::Burn(args) => burn_altitudes(args), // network burnサブコマンドが呼ばれた場合
+        },
     }
 }
 
@@ -0,0 +1,291 @@
+use std::fs::canonicalize;
+use std::path::{Path, PathBuf};
+
+use coordinate_transformer::ZoomLv;
+use futures::future;
+use indicatif::{ProgressBar, ProgressStyle};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::collect::{parse_tile_path, read_tile_list, Region};
+use crate::dem::{dem_url_template, split_dem_source, tile_layout, tile_url};
+use crate::fetch::{write_atomic, TileFetcher};
+use crate::mokuroku::{download_mokuroku, read_mokuroku, write_mokuroku};
+use crate::MirrorArgs;
+
+/// mirrorサブコマンド用の関数
+/// mokurokuに記載された河川タイルと、それらを覆うDEMタイル(補間で参照する周囲のタイルと、存在しなかったタイルを補う低いズームレベルのタイルを含む)をローカルのディレクトリに保存する
+/// 保存先は`{output}/river/{z}/{x}/{y}.geojson`と、`{output}/dem/`にDEMタイルのURLのテンプレートのパスの部分(gsjの既定では`{z}/{y}/{x}.png`)を続けたもので、
+/// collectの`--river-base-url`と`--dem-base-url`(または`--dem-url`)にそれぞれのディレクトリを指定するとオフラインで処理できる
+/// `--dem-source`を指定した場合は、タイルのDEMごとに`{output}/dem/{指定した順番}/`に保存する
+pub async fn mirror_tiles(args: &MirrorArgs) {
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_message("Initializing...");
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let MirrorArgs {
+        mokuroku,
+        fetch_mokuroku,
+        batch: batch_size,
+        river_base_url,
+        dem_base_url,
+        dem_url,
+        dem_format,
+        dem_source,
+        zoom_lv,
+        dem_min_zoom,
+        aabb,
+        aoi,
+        select,
+        output,
+    } = args;
+    if *fetch_mokuroku {
+        spinner.set_message("Fetching mokuroku.csv.gz...");
+        download_mokuroku(river_base_url, Path::new(mokuroku)).await;
+    }
+    let mokuroku = canonicalize(mokuroku).expect("Failed to canonicalize mokuroku file path");
+    let dem_zoom_lv = ZoomLv::parse(*zoom_lv).expect("Failed to parse ZoomLv");
+    let region = Region::from_args(aabb, aoi, *select);
+
+    let river_dir = PathBuf::from(output).join("river");
+    let dem_dir = PathBuf::from(output).join("dem");
+
+    // (FORMAT, DEMタイルのURLのテンプレート, 保存先のディレクトリ)
+    let dem_sources = if dem_source.is_empty() {
+        vec![(None, dem_url_template(dem_url, dem_base_url, *dem_format), dem_dir)]
+    } else {
+        dem_source
+            .iter()
+            .enumerate()
+            .filter_map(|(i, spec)| match split_dem_source(spec) {
+                (Some(_), url_template) => {
+                    let format = spec.split_once('=').map(|(format, _)| format.to_string());
+                    Some((format, url_template.to_string(), dem_dir.join(i.to_string())))
+                }
+                (None, location) => {
+                    eprintln!("Skipping the local GeoTIFF DEM source {:?}", location);
+                    None
+                }
+            })
+            .collect()
+    };
+
+    spinner.set_message("Reading mokuroku.csv...");
+    let entries = read_tile_list(&mokuroku, region.as_ref());
+
+    std::fs::create_dir_all(&river_dir)
+        .unwrap_or_else(|e| panic!("Failed to create directory {:?}: {:#?}", river_dir, e));
+
+    // 前回ミラーした時点のタイルの一覧と比較し、更新されたタイルをダウンロードし直す
+    let mirrored_mokuroku = river_dir.join("mokuroku.csv");
+    let previous = if mirrored_mokuroku.exists() {
+        read_mokuroku(&mirrored_mokuroku)
+    } else {
+        Vec::new()
+    };
+    let previous_map = previous
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect::<FxHashMap<_, _>>();
+
+    // 河川タイルを覆うDEMタイルと、補間で参照する周囲のDEMタイルの一覧
+    let dem_tiles = entries
+        .iter()
+        .flat_map(|entry| covering_tiles(parse_tile_path(&entry.path), dem_zoom_lv))
+        .flat_map(|tile| neighbor_tiles(tile, dem_zoom_lv))
+        .collect::<FxHashSet<_>>();
+    spinner.finish_and_clear();
+
+    let fetcher = TileFetcher::new(None);
+
+    let river_downloads = entries
+        .iter()
+        .map(|entry| {
+            let modified = previous_map
+                .get(entry.path.as_str())
+                .is_none_or(|prev| prev.is_modified(entry));
+            (
+                format!("{river_base_url}{}", entry.path),
+                river_dir.join(&entry.path),
+                modified,
+            )
+        })
+        .collect::<Vec<_>>();
+
+    let pb = ProgressBar::new((river_downloads.len() + dem_tiles.len() * dem_sources.len()) as u64);
+    pb.set_message("Downloading tiles...");
+    pb.set_style(
+        ProgressStyle::with_template("{msg}\n[{elapsed_precise}] {wide_bar} {pos}/{len} ({eta_precise})")
+            .unwrap(),
+    );
+
+    let found = download_tiles(&river_downloads, *batch_size, &fetcher, &pb).await;
+    let mut downloaded = found.iter().filter(|found| **found).count();
+    let mut missing = found.len() - downloaded;
+
+    // ミラーしたディレクトリをそのまま--mokurokuとして使えるよう、対象のタイルの一覧を保存する
+    // 更新されたタイルを取得できなかった場合は前回の内容を記録し、次回のミラーで取得し直す
+    let mirrored = entries
+        .iter()
+        .zip(&found)
+        .map(|(entry, found)| match previous_map.get(entry.path.as_str()) {
+            Some(prev) if !*found => (*prev).clone(),
+            _ => entry.clone(),
+        })
+        .collect::<Vec<_>>();
+    write_mokuroku(&mirrored_mokuroku, &mirrored);
+
+    let dem_tiles = dem_tiles.into_iter().collect::<Vec<_>>();
+    for (_, dem_template, dir) in &dem_sources {
+        let (found, not_found) = mirror_dem(
+            &dem_tiles,
+            (dem_template, dir),
+            (dem_zoom_lv as u8, *dem_min_zoom),
+            *batch_size,
+            &fetcher,
+            &pb,
+        )
+        .await;
+        downloaded += found;
+        missing += not_found;
+    }
+
+    pb.finish_with_message(format!(
+        "Mirrored {} tiles to {:?} ({} not found on the server)",
+        downloaded, output, missing
+    ));
+
+    // collectで同じ順にDEMを参照するための--dem-source
+    for (format, dem_template, dir) in &dem_sources {
+        if let Some(format) = format {
+            eprintln!(
+                "--dem-source '{}={}'",
+                format,
+                dir.join(tile_layout(dem_template)).to_string_lossy()
+            );
+        }
+    }
+}
+
+/// DEMタイルをダウンロードし、(保存したタイルの数, 存在しなかったタイルの数)を返す
+/// 存在しなかったDEMタイルは、collectと同じく`min_zoom`まで低いズームレベルのタイルで補う
+async fn mirror_dem(
+    tiles: &[(u32, u32)],
+    (dem_template, dir): (&str, &Path),
+    (zoom_lv, min_zoom): (u8, u8),
+    batch_size: usize,
+    fetcher: &TileFetcher,
+    pb: &ProgressBar,
+) -> (usize, usize) {
+    let dem_layout = tile_layout(dem_template);
+    let (mut downloaded, mut missing) = (0, 0);
+
+    let mut dem_tiles = tiles.to_vec();
+    let mut z = zoom_lv;
+    while !dem_tiles.is_empty() {
+        let dem_downloads = dem_tiles
+            .iter()
+            .map(|(x, y)| {
+                (
+                    tile_url(dem_template, z, *x, *y),
+                    dir.join(tile_url(dem_layout, z, *x, *y)),
+                    false,
+                )
+            })
+            .collect::<Vec<_>>();
+        let found = download_tiles(&dem_downloads, batch_size, fetcher, pb).await;
+        downloaded += found.iter().filter(|found| **found).count();
+        missing += found.iter().filter(|found| !**found).count();
+
+        if z <= min_zoom {
+            break;
+        }
+        z -= 1;
+        dem_tiles = dem_tiles
+            .iter()
+            .zip(&found)
+            .filter(|(_, found)| !**found)
+            .map(|((x, y), _)| (x >> 1, y >> 1))
+            .collect::<FxHashSet<_>>()
+            .into_iter()
+            .collect();
+        pb.inc_length(dem_tiles.len() as u64);
+    }
+    (downloaded, missing)
+}
+
+/// タイルを`batch_size`ずつダウンロードし、それぞれがサーバーに存在したかを返す
+/// 各要素は(URL, 保存先, 保存済みでもダウンロードし直すか)
+async fn download_tiles(
+    downloads: &[(String, PathBuf, bool)],
+    batch_size: usize,
+    fetcher: &TileFetcher,
+    pb: &ProgressBar,
+) -> Vec<bool> {
+    let mut found = Vec::with_capacity(downloads.len());
+    for batch in downloads.chunks(batch_size) {
+        let futures = batch
+            .iter()
+            .map(|(url, path, refresh)| download_tile(url, path, *refresh, fetcher));
+        found.extend(future::join_all(futures).await);
+
+        pb.inc(batch.len() as u64);
+    }
+    found
+}
+
+/// タイルをダウンロードして保存する
+/// `refresh`が指定されない限り既に保存済みのタイルはスキップし、サーバーにタイルが存在しなかった場合は`false`を返す
+async fn download_tile(url: &str, path: &Path, refresh: bool, fetcher: &TileFetcher) -> bool {
+    if !refresh && path.exists() {
+        return true;
+    }
+
+    const MAX_RETRY: usize = 5;
+
+    let mut current_retry = 0;
+    loop {
+        match fetcher.fetch(url).await {
+            Ok(Some(body)) => {
+                write_atomic(path, &body)
+                    .await
+                    .unwrap_or_else(|e| panic!("Failed to save tile to {:?}: {:#?}", path, e));
+                return true;
+            }
+            Ok(None) => return false,
+            Err(e) => {
+                eprintln!("Error: {:#?}", e);
+                current_retry += 1;
+                if current_retry >= MAX_RETRY {
+                    eprintln!("Failed to fetch tile data from URL: {}", url);
+                    return false;
+                }
+            }
+        }
+    }
+}
+
+/// あるタイルを覆う、指定したズームレベルのタイルの一覧
+fn covering_tiles((z, x, y): (ZoomLv, u32, u32), target: ZoomLv) -> Vec<(u32, u32)> {
+    let (z, target) = (z as u32, target as u32);
+
+    if target <= z {
+        let shift = z - target;
+        vec![(x >> shift, y >> shift)]
+    } else {
+        let shift = target - z;
+        let (min_x, min_y) = (x << shift, y << shift);
+        (min_x..min_x + (1 << shift))
+            .flat_map(|x| (min_y..min_y + (1 << shift)).map(move |y| (x, y)))
+            .collect()
+    }
+}
+
+/// タイルとその周囲8つのタイル
+fn neighbor_tiles((x, y): (u32, u32), z: ZoomLv) -> Vec<(u32, u32)> {
+    let size = 1_i64 << z as u32;
+    (-1..=1)
+        .flat_map(|dy| (-1..=1).map(move |dx| (x as i64 + dx, y as i64 + dy)))
+        .filter(|(_, y)| (0..size).contains(y))
+        .map(|(x, y)| (x.rem_euclid(size) as u32, y as u32))
+        .collect()
+}
@@ -0,0 +1,126 @@
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::path::Path;
+
+use csv::{ReaderBuilder, WriterBuilder};
+use flate2::read::GzDecoder;
+
+use crate::fetch::{write_atomic, TileFetcher};
+
+/// mokurokuファイルの1行分の情報
+/// ex) 18/232837/103222.geojson,1401003812,2155,9f3c3b0a0d9b1b4f0ed4b4b0a0e6d8f1
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TileEntry {
+    /// タイルのURLの後半部分 ({z}/{x}/{y}.geojson)
+    pub path: String,
+    /// 最終更新日時(UNIX時間)
+    pub lastmod: Option<u64>,
+    /// ファイルサイズ
+    pub size: Option<u64>,
+    /// MD5ハッシュ値
+    pub md5: String,
+}
+
+impl TileEntry {
+    /// タイルの内容が変更されているかを判定する
+    /// MD5が記録されていない場合は最終更新日時とサイズで比較する
+    pub fn is_modified(&self, other: &TileEntry) -> bool {
+        if !self.md5.is_empty() && !other.md5.is_empty() {
+            self.md5 != other.md5
+        } else {
+            self.lastmod != other.lastmod || self.size != other.size
+        }
+    }
+}
+
+/// gzipファイルの先頭2バイト
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// mokurokuファイルを読み込む
+/// gzipで圧縮されたファイル(mokuroku.csv.gz)はそのまま展開して読み込む
+/// mokurokuファイルにはヘッダーが無いため、先頭が数字で始まらない行は読み飛ばす
+pub fn read_mokuroku(path: &Path) -> Vec<TileEntry> {
+    let file = File::open(path)
+        .unwrap_or_else(|_| panic!("Failed to read mokuroku CSV file at {:?}", path));
+    let mut reader = BufReader::new(file);
+
+    let is_gzip = reader
+        .fill_buf()
+        .unwrap_or_else(|_| panic!("Failed to read mokuroku CSV file at {:?}", path))
+        .starts_with(&GZIP_MAGIC);
+    let reader: Box<dyn Read> = if is_gzip {
+        Box::new(GzDecoder::new(reader))
+    } else {
+        Box::new(reader)
+    };
+
+    ReaderBuilder::new()
+        .has_headers(false)
+        .flexible(true)
+        .from_reader(reader)
+        .into_records()
+        .filter_map(|record| {
+            let record = record.ok()?;
+            let url = record.get(0)?;
+            if !url.chars().next()?.is_ascii_digit() {
+                return None;
+            }
+
+            Some(TileEntry {
+                path: url.to_string(),
+                lastmod: record.get(1).and_then(|s| s.parse().ok()),
+                size: record.get(2).and_then(|s| s.parse().ok()),
+                md5: record.get(3).unwrap_or_default().to_string(),
+            })
+        })
+        .collect()
+}
+
+/// mokurokuファイルと同じ形式でタイルの一覧を書き出す
+pub fn write_mokuroku(path: &Path, entries: &[TileEntry]) {
+    let mut writer = WriterBuilder::new()
+        .has_headers(false)
+        .from_path(path)
+        .unwrap_or_else(|_| panic!("Failed to create mokuroku CSV file at {:?}", path));
+
+    for entry in entries {
+        writer
+            .write_record([
+                entry.path.clone(),
+                entry.lastmod.map(|v| v.to_string()).unwrap_or_default(),
+                entry.size.map(|v| v.to_string()).unwrap_or_default(),
+                entry.md5.clone(),
+            ])
+            .unwrap_or_else(|_| panic!("Failed to write mokuroku CSV file at {:?}", path));
+    }
+
+    writer
+        .flush()
+        .unwrap_or_else(|_| panic!("Failed to flush mokuroku CSV file at {:?}", path));
+}
+
+/// 河川データのベースURLからmokuroku.csv.gzを取得して保存する
+/// 保存先の拡張子が`.gz`でない場合は展開して保存する
+pub async fn download_mokuroku(river_base_url: &str, path: &Path) {
+    let url = format!("{river_base_url}mokuroku.csv.gz");
+
+    let body = TileFetcher::new(None)
+        .fetch(&url)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to fetch mokuroku from URL: {}: {:#?}", url, e))
+        .unwrap_or_else(|| panic!("mokuroku not found at URL: {}", url));
+
+    let body = if path.extension().is_some_and(|ext| ext == "gz") {
+        body
+    } else {
+        let mut decompressed = Vec::new();
+        GzDecoder::new(body.as_slice())
+            .read_to_end(&mut decompressed)
+            .unwrap_or_else(|e| panic!("Failed to decompress mokuroku from URL: {}: {:#?}", url, e));
+        decompressed
+    };
+
+    write_atomic(path, &body)
+        .await
+        .unwrap_or_else(|e| panic!("Failed to save mokuroku to {:?}: {:#?}", path, e));
+}
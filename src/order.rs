@@ -0,0 +1,136 @@
+use std::path::Path;
+
+use indicatif::ProgressBar;
+use rustc_hash::FxHashSet;
+
+use crate::graph::{require_directed, RiverGraph, Table};
+use crate::update::write_rows;
+use crate::NetworkArgs;
+
+/// リンクごとの河川次数
+#[derive(Debug, Clone, Copy, Default)]
+struct StreamOrder {
+    strahler: u32,
+    shreve: u64,
+    horton: u32,
+    /// 最も長い上流の経路の長さ[m] (ホートン次数の本流を決めるために使う)
+    upstream_length: f64,
+}
+
+/// `network order`サブコマンド用の関数
+/// FLOWS_TOリンクについてストレーラー次数、シュリーブ次数、ホートン次数を求め、リンクの列に書き込む
+pub fn compute_stream_order(args: &NetworkArgs) {
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let nodes_path = Path::new(&args.nodes);
+    let links_path = Path::new(&args.links);
+
+    spinner.set_message("Reading nodes and links...");
+    let nodes = Table::read(nodes_path);
+    let mut links = Table::read(links_path);
+    require_directed(&links);
+    let graph = RiverGraph::new(&nodes, &links);
+
+    // 上流から下流へトポロジカル順にノードを処理する
+    spinner.set_message("Calculating Strahler and Shreve orders...");
+    let topological = graph.topological_order();
+    let mut orders = vec![None::<StreamOrder>; graph.links.len()];
+
+    for &node in &topological {
+        let upstream = graph
+            .incoming(node)
+            .filter_map(|link| orders[link])
+            .collect::<Vec<_>>();
+        let max_strahler = upstream.iter().map(|o| o.strahler).max().unwrap_or(0);
+        let order = if upstream.is_empty() {
+            // 源流
+            StreamOrder {
+                strahler: 1,
+                shreve: 1,
+                ..Default::default()
+            }
+        } else {
+            let max_count = upstream.iter().filter(|o| o.strahler == max_strahler).count();
+            StreamOrder {
+                strahler: if max_count >= 2 { max_strahler + 1 } else { max_strahler },
+                shreve: upstream.iter().map(|o| o.shreve).sum(),
+                upstream_length: upstream.iter().map(|o| o.upstream_length).fold(0., f64::max),
+                ..Default::default()
+            }
+        };
+
+        // 分岐点では全ての下流のリンクに同じ次数を引き継ぐ
+        for link in graph.outgoing(node) {
+            orders[link] = Some(StreamOrder {
+                upstream_length: order.upstream_length + graph.links[link].length,
+                horton: order.strahler,
+                ..order
+            });
+        }
+    }
+
+    // 下流から上流へ、本流(次数が最も高く、最も長い上流を持つリンク)に下流のホートン次数を引き継ぐ
+    spinner.set_message("Calculating Horton orders...");
+    for &node in topological.iter().rev() {
+        let Some(downstream_horton) = graph
+            .outgoing(node)
+            .filter_map(|link| orders[link].map(|o| o.horton))
+            .max()
+        else {
+            continue;
+        };
+        let main = graph
+            .incoming(node)
+            .filter(|link| orders[*link].is_some())
+            .max_by(|a, b| {
+                let (a, b) = (orders[*a].unwrap(), orders[*b].unwrap());
+                a.strahler
+                    .cmp(&b.strahler)
+                    .then(a.upstream_length.total_cmp(&b.upstream_length))
+            });
+        if let Some(order) = main.and_then(|link| orders[link].as_mut()) {
+            order.horton = order.horton.max(downstream_horton);
+        }
+    }
+
+    spinner.set_message("Writing links...");
+    let mut columns = [Vec::new(), Vec::new(), Vec::new()];
+    let mut rows = vec![None; links.rows.len()];
+    for (link, order) in graph.links.iter().zip(&orders) {
+        rows[link.row] = *order;
+    }
+    for order in rows {
+        let values = match order {
+            Some(o) => [o.strahler.to_string(), o.shreve.to_string(), o.horton.to_string()],
+            None => Default::default(),
+        };
+        for (column, value) in columns.iter_mut().zip(values) {
+            column.push(value);
+        }
+    }
+    let [strahler, shreve, horton] = columns;
+    links.set_column("strahler:int", strahler);
+    links.set_column("shreve:int", shreve);
+    links.set_column("horton:int", horton);
+    links.write(links_path);
+
+    // 循環に含まれる、または循環の下流にあるため次数を求められなかったノードを書き出す
+    let resolved = topological.iter().copied().collect::<FxHashSet<_>>();
+    let mut unresolved = (0..graph.node_count())
+        .filter(|node| !resolved.contains(node))
+        .map(|node| graph.ids[node])
+        .collect::<Vec<_>>();
+    unresolved.sort_unstable();
+    let unresolved = unresolved.iter().map(usize::to_string).collect::<Vec<_>>();
+    let report_path = links_path.with_file_name("river_order_cycle.csv");
+    write_rows(&report_path, "id", &unresolved);
+
+    spinner.finish_with_message(format!(
+        "Computed stream order for {} of {} links ({} nodes in or below cycles written to {:?})",
+        orders.iter().filter(|o| o.is_some()).count(),
+        graph.links.len(),
+        unresolved.len(),
+        report_path,
+    ));
+}
@@ -0,0 +1,220 @@
+use std::path::Path;
+
+use indicatif::ProgressBar;
+
+use crate::graph::{find_node_id_column, RiverGraph, Table};
+use crate::NetworkArgs;
+
+/// `network reach`サブコマンド用の関数
+/// 分岐点・合流点・源流・河口をJunctionノードとし、その間の次数2のノードの連なりを1本のREACHリンクにまとめる
+/// 結果はriver_junction.csvとriver_reach.csvに書き出す (元のノードとリンクはそのまま残す)
+pub fn build_reaches(args: &NetworkArgs) {
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let nodes_path = Path::new(&args.nodes);
+    let links_path = Path::new(&args.links);
+
+    spinner.set_message("Reading nodes and links...");
+    let nodes = Table::read(nodes_path);
+    let links = Table::read(links_path);
+    let graph = RiverGraph::new(&nodes, &links);
+
+    // FLOWS_TOに向き付けられている場合は流下方向に沿ってまとめる
+    let directed = links
+        .column(":TYPE")
+        .is_some_and(|column| links.rows.iter().all(|row| row[column] == "FLOWS_TO"));
+
+    spinner.set_message("Finding junctions...");
+    let mut kinds = (0..graph.node_count())
+        .map(|node| junction_kind(&graph, node, directed))
+        .collect::<Vec<_>>();
+
+    spinner.set_message("Collapsing reaches...");
+    let mut visited = vec![false; graph.links.len()];
+    let mut reaches = Vec::new();
+    for start in 0..graph.node_count() {
+        if kinds[start].is_some() {
+            walk_reaches(&graph, start, directed, &kinds, &mut visited, &mut reaches);
+        }
+    }
+    // 分岐の無い環状の河川は、その中の1つのノードをJunctionとする
+    for link in 0..graph.links.len() {
+        if !visited[link] {
+            let start = graph.links[link].start;
+            kinds[start] = Some("loop");
+            walk_reaches(&graph, start, directed, &kinds, &mut visited, &mut reaches);
+        }
+    }
+
+    spinner.set_message("Writing junctions and reaches...");
+    let id_column = &nodes.header[find_node_id_column(&nodes)];
+    let junction_header = [
+        format!("{}(Junction)", id_column),
+        "location:point{crs:WGS-84}".to_string(),
+        "altitude:float".to_string(),
+        "kind:string".to_string(),
+        ":LABEL".to_string(),
+    ];
+    let junctions = Table {
+        header: junction_header.to_vec(),
+        rows: kinds
+            .iter()
+            .enumerate()
+            .filter_map(|(node, kind)| {
+                let (long, lat) = graph.coords[node];
+                Some(vec![
+                    graph.ids[node].to_string(),
+                    format!("{{longitude:{long},latitude:{lat}}}"),
+                    format_altitude(graph.altitudes[node]),
+                    kind.as_ref()?.to_string(),
+                    "Junction".to_string(),
+                ])
+            })
+            .collect(),
+    };
+    junctions.write(&links_path.with_file_name("river_junction.csv"));
+
+    let reach_header = [
+        ":START_ID(Junction)",
+        ":END_ID(Junction)",
+        ":TYPE",
+        "length:float",
+        "vertex_count:int",
+        "min_altitude:float",
+        "max_altitude:float",
+        "geometry:string",
+    ];
+    let reach_table = Table {
+        header: reach_header.map(str::to_string).to_vec(),
+        rows: reaches
+            .iter()
+            .map(|reach| {
+                let altitudes = reach
+                    .vertices
+                    .iter()
+                    .filter_map(|node| graph.altitudes[*node])
+                    .collect::<Vec<_>>();
+                let min = altitudes.iter().copied().reduce(f32::min);
+                let max = altitudes.iter().copied().reduce(f32::max);
+                let coords = reach
+                    .vertices
+                    .iter()
+                    .map(|node| graph.coords[*node])
+                    .collect::<Vec<_>>();
+
+                vec![
+                    graph.ids[reach.vertices[0]].to_string(),
+                    graph.ids[*reach.vertices.last().unwrap()].to_string(),
+                    "REACH".to_string(),
+                    format!("{:.3}", reach.length),
+                    reach.vertices.len().to_string(),
+                    format_altitude(min),
+                    format_altitude(max),
+                    encode_polyline(&coords, 6),
+                ]
+            })
+            .collect(),
+    };
+    reach_table.write(&links_path.with_file_name("river_reach.csv"));
+
+    spinner.finish_with_message(format!(
+        "Collapsed {} links into {} reaches between {} junctions",
+        graph.links.len(),
+        reaches.len(),
+        junctions.rows.len(),
+    ));
+}
+
+/// Junctionの間の次数2のノードの連なり
+struct Reach {
+    /// 両端のJunctionを含む頂点
+    vertices: Vec<usize>,
+    /// 長さ[m]
+    length: f64,
+}
+
+/// ノードがJunctionであれば、その種類を返す
+fn junction_kind(graph: &RiverGraph, node: usize, directed: bool) -> Option<&'static str> {
+    if !directed {
+        return match graph.degree(node) {
+            2 => None,
+            0 | 1 => Some("end"),
+            _ => Some("junction"),
+        };
+    }
+
+    let incoming = graph.incoming(node).count();
+    let outgoing = graph.outgoing(node).count();
+    match (incoming, outgoing) {
+        (1, 1) => None,
+        (0, _) => Some("source"),
+        (_, 0) => Some("mouth"),
+        (i, 1) if i > 1 => Some("confluence"),
+        (1, _) => Some("bifurcation"),
+        _ => Some("junction"),
+    }
+}
+
+/// Junctionから出るリンクを次のJunctionまで辿る
+fn walk_reaches(
+    graph: &RiverGraph,
+    start: usize,
+    directed: bool,
+    kinds: &[Option<&'static str>],
+    visited: &mut [bool],
+    reaches: &mut Vec<Reach>,
+) {
+    for &first in &graph.adjacency[start] {
+        if visited[first] || (directed && graph.links[first].start != start) {
+            continue;
+        }
+
+        let mut vertices = vec![start];
+        let mut length = 0.;
+        let mut link = first;
+        let mut node = start;
+        loop {
+            visited[link] = true;
+            length += graph.links[link].length;
+            node = graph.links[link].other(node);
+            vertices.push(node);
+            if kinds[node].is_some() {
+                break;
+            }
+            // 次数2のノードでは、もう一方のリンクに進む
+            let Some(&next) = graph.adjacency[node].iter().find(|l| **l != link && !visited[**l]) else {
+                break;
+            };
+            link = next;
+        }
+
+        reaches.push(Reach { vertices, length });
+    }
+}
+
+fn format_altitude(altitude: Option<f32>) -> String {
+    altitude.map(|altitude| altitude.to_string()).unwrap_or_default()
+}
+
+/// 座標列をEncoded Polyline Algorithm Formatで符号化する (precisionは小数点以下の桁数、6でpolyline6)
+fn encode_polyline(coords: &[(f64, f64)], precision: i32) -> String {
+    let factor = 10_f64.powi(precision);
+    let mut encoded = String::new();
+    let mut previous = (0_i64, 0_i64);
+
+    for &(long, lat) in coords {
+        let current = ((lat * factor).round() as i64, (long * factor).round() as i64);
+        for delta in [current.0 - previous.0, current.1 - previous.1] {
+            let mut value = if delta < 0 { !(delta << 1) } else { delta << 1 };
+            while value >= 0x20 {
+                encoded.push((((0x20 | (value & 0x1f)) + 63) as u8) as char);
+                value >>= 5;
+            }
+            encoded.push(((value + 63) as u8) as char);
+        }
+        previous = current;
+    }
+
+    encoded
+}
@@ -0,0 +1,269 @@
+use std::path::Path;
+
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::collect::{parse_tile_path, tile_bounds, LinkGeometry, LINK_GEOMETRY_COLUMNS};
+use crate::distance::DistanceMeasure;
+use crate::graph::Table;
+use crate::update::{parse_id, parse_link_key, read_rows, write_rows};
+
+/// タイルの境界付近にある端点
+struct Endpoint {
+    id: usize,
+    long: f64,
+    lat: f64,
+    /// 端点に接続するリンクが含まれるタイル
+    tile: String,
+    /// タイル座標
+    tile_xy: (u32, u32),
+}
+
+/// タイルの境界で分断された中心線を繋ぎ合わせる
+///
+/// 次数1のノードのうち、mokurokuのタイルの境界から`tolerance`[m]以内にあるものを端点として集め、
+/// 隣接する別のタイルの端点と`tolerance`[m]以内にあれば1つのノードに統合する。
+/// 距離はリンクの長さと同じく`distance`で求める。
+/// 統合されたノードのIDは最小のIDに揃え、リンクとタイルの対応も書き換える。
+/// 端点が移動したリンクの形状の列は、`distance`で長さを求めて計算し直す。
+/// 統合した端点と相手が見つからなかった端点は`report_path`に書き出す。
+/// 戻り値は統合によって削除されたノードの数
+pub(crate) fn stitch_tiles(
+    nodes_path: &Path,
+    links_path: &Path,
+    index_path: &Path,
+    report_path: &Path,
+    tolerance: f64,
+    distance: &DistanceMeasure,
+) -> usize {
+    // リンクには引用符で囲まれたプロパティが含まれるため、CSVとして読み書きする
+    let mut links = Table::read(links_path);
+    let start_column = links.expect_column(":START_ID");
+    let end_column = links.expect_column(":END_ID");
+    let (index_header, index_rows) = read_rows(index_path);
+
+    // ノードの次数
+    let mut degree = FxHashMap::<usize, usize>::default();
+    for row in &links.rows {
+        let (id1, id2) = (parse_id(Some(&row[start_column])), parse_id(Some(&row[end_column])));
+        *degree.entry(id1).or_default() += 1;
+        *degree.entry(id2).or_default() += 1;
+    }
+
+    // 次数1のノードが属するタイル
+    let mut endpoint_tiles = FxHashMap::<usize, String>::default();
+    for row in &index_rows {
+        let mut iter = row.split(',');
+        let tile = iter.next().unwrap_or_default();
+        let (id1, id2) = parse_link_key(iter);
+        for id in [id1, id2] {
+            if degree.get(&id) == Some(&1) {
+                endpoint_tiles.entry(id).or_insert_with(|| tile.to_string());
+            }
+        }
+    }
+
+    let (nodes_header, node_rows) = read_rows(nodes_path);
+    let endpoints = node_rows
+        .iter()
+        .filter_map(|row| {
+            let id = parse_id(row.split(',').next());
+            let tile = endpoint_tiles.get(&id)?;
+            let (long, lat) = parse_location(row)?;
+
+            let (z, x, y) = parse_tile_path(tile);
+            let (min, max) = tile_bounds((z, x, y));
+            let edge_distance = [
+                (min.0, lat),
+                (max.0, lat),
+                (long, min.1),
+                (long, max.1),
+            ]
+                .into_iter()
+                .map(|(edge_long, edge_lat)| distance.distance_m((long, lat), (edge_long, edge_lat)))
+                .fold(f64::INFINITY, f64::min);
+
+            (edge_distance <= tolerance).then(|| Endpoint {
+                id,
+                long,
+                lat,
+                tile: tile.clone(),
+                tile_xy: (x, y),
+            })
+        })
+        .collect::<Vec<_>>();
+
+    // 近傍探索用のグリッド
+    // 経度方向の1度は高緯度ほど短くなるため、最も高緯度の端点に合わせてセルの大きさを決める
+    // 1度の長さは楕円体上の緯線方向の最小値(約110.57km)より短く見積もり、セルが許容値より小さくならないようにする
+    let max_abs_lat = endpoints.iter().map(|e| e.lat.abs()).fold(0., f64::max);
+    let cell_size = tolerance.max(f64::EPSILON) / (110_000. * max_abs_lat.to_radians().cos().max(0.01));
+    let cell_of = |e: &Endpoint| ((e.long / cell_size).floor() as i64, (e.lat / cell_size).floor() as i64);
+    let mut grid = FxHashMap::<(i64, i64), Vec<usize>>::default();
+    for (i, e) in endpoints.iter().enumerate() {
+        grid.entry(cell_of(e)).or_default().push(i);
+    }
+
+    // 隣接するタイルの端点同士をUnion-Findでまとめる
+    let mut parent = (0..endpoints.len()).collect::<Vec<_>>();
+    fn find(parent: &mut [usize], i: usize) -> usize {
+        let mut root = i;
+        while parent[root] != root {
+            root = parent[root];
+        }
+        let mut i = i;
+        while parent[i] != root {
+            let next = parent[i];
+            parent[i] = root;
+            i = next;
+        }
+        root
+    }
+
+    for (i, a) in endpoints.iter().enumerate() {
+        let (cx, cy) = cell_of(a);
+        for dx in -1..=1 {
+            for dy in -1..=1 {
+                let Some(cell) = grid.get(&(cx + dx, cy + dy)) else {
+                    continue;
+                };
+                for &j in cell {
+                    let b = &endpoints[j];
+                    if j <= i
+                        || a.tile == b.tile
+                        || a.tile_xy.0.abs_diff(b.tile_xy.0) > 1
+                        || a.tile_xy.1.abs_diff(b.tile_xy.1) > 1
+                        || distance.distance_m((a.long, a.lat), (b.long, b.lat)) > tolerance
+                    {
+                        continue;
+                    }
+                    let (ra, rb) = (find(&mut parent, i), find(&mut parent, j));
+                    if ra != rb {
+                        parent[ra.max(rb)] = ra.min(rb);
+                    }
+                }
+            }
+        }
+    }
+
+    // 各グループの中で最小のIDを残す
+    let mut survivors = FxHashMap::<usize, usize>::default();
+    let mut group_size = FxHashMap::<usize, usize>::default();
+    for (i, e) in endpoints.iter().enumerate() {
+        let root = find(&mut parent, i);
+        let id = survivors.entry(root).or_insert(e.id);
+        *id = (*id).min(e.id);
+        *group_size.entry(root).or_default() += 1;
+    }
+
+    let mut replace = FxHashMap::<usize, usize>::default();
+    let mut report = Vec::new();
+    for (i, e) in endpoints.iter().enumerate() {
+        let root = find(&mut parent, i);
+        let survivor = survivors[&root];
+        let status = if group_size[&root] > 1 {
+            if survivor != e.id {
+                replace.insert(e.id, survivor);
+            }
+            "stitched"
+        } else {
+            "unmatched"
+        };
+        let merged_into = if status == "stitched" { survivor.to_string() } else { String::new() };
+        report.push(format!("{status},{},{},{},{},{merged_into}", e.id, e.long, e.lat, e.tile));
+    }
+    report.sort_unstable();
+    write_rows(report_path, "status,id,longitude,latitude,tile,merged_into", &report);
+
+    if replace.is_empty() {
+        return 0;
+    }
+
+    // 端点が移動したリンクの形状を計算し直すための、ノードの(経度, 緯度, 標高)
+    let coords = node_rows
+        .iter()
+        .filter_map(|row| {
+            let (long, lat) = parse_location(row)?;
+            Some((parse_id(row.split(',').next()), (long, lat, parse_altitude(row))))
+        })
+        .collect::<FxHashMap<_, _>>();
+    let link_geometry = |id1: usize, id2: usize| {
+        let (Some(&start), Some(&end)) = (coords.get(&id1), coords.get(&id2)) else {
+            panic!("Node {} or {} of a stitched link is missing in {:?}", id1, id2, nodes_path);
+        };
+        let length = distance.distance_m((start.0, start.1), (end.0, end.1));
+        LinkGeometry::new(start, end, length)
+    };
+
+    // リンクとタイルの対応のIDを書き換え、(始点, 終点)までの列が重複した行と始点と終点が同じ行を除く
+    // `geometry_column`が指定された場合は、端点が移動したリンクのその列から形状の列を書き直す
+    let rewrite = |rows: Vec<Vec<String>>, (start, end): (usize, usize), geometry_column: Option<usize>| {
+        let mut seen = FxHashSet::default();
+        rows.into_iter()
+            .filter_map(|mut fields| {
+                let mut moved = false;
+                for column in [start, end] {
+                    let id = parse_id(Some(&fields[column]));
+                    if let Some(survivor) = replace.get(&id) {
+                        fields[column] = survivor.to_string();
+                        moved = true;
+                    }
+                }
+                if let Some(column) = geometry_column.filter(|_| moved) {
+                    let geometry = link_geometry(parse_id(Some(&fields[start])), parse_id(Some(&fields[end])));
+                    for (field, value) in fields[column..column + LINK_GEOMETRY_COLUMNS.len()]
+                        .iter_mut()
+                        .zip(geometry.columns())
+                    {
+                        *field = value;
+                    }
+                }
+                let key = fields[..=start.max(end)].to_vec();
+                (fields[start] != fields[end] && seen.insert(key)).then_some(fields)
+            })
+            .collect::<Vec<_>>()
+    };
+    let geometry_column = links.expect_column(LINK_GEOMETRY_COLUMNS[0]);
+    links.rows = rewrite(
+        std::mem::take(&mut links.rows),
+        (start_column, end_column),
+        Some(geometry_column),
+    );
+    links.write(links_path);
+
+    // タイルのパスとIDにはコンマが含まれないため、行をそのまま分割する
+    let index_rows = index_rows
+        .iter()
+        .map(|row| row.split(',').map(str::to_string).collect())
+        .collect();
+    let index_rows = rewrite(index_rows, (1, 2), None)
+        .into_iter()
+        .map(|fields| fields.join(","))
+        .collect::<Vec<_>>();
+    write_rows(index_path, &index_header, &index_rows);
+
+    let node_rows = node_rows
+        .into_iter()
+        .filter(|row| !replace.contains_key(&parse_id(row.split(',').next())))
+        .collect::<Vec<_>>();
+    write_rows(nodes_path, &nodes_header, &node_rows);
+
+    replace.len()
+}
+
+/// ノードの行から経度と緯度を読み取る
+/// ex) 3412033,"{longitude:135.343717784783,latitude:35.1782983520012}",197.95,RiverNode
+pub(crate) fn parse_location(row: &str) -> Option<(f64, f64)> {
+    let value = |key: &str| {
+        let start = row.find(key)? + key.len();
+        let rest = &row[start..];
+        let end = rest.find([',', '}'])?;
+        rest[..end].parse::<f64>().ok()
+    };
+
+    Some((value("longitude:")?, value("latitude:")?))
+}
+
+/// ノードの行から標高を読み取る (標高が無い場合は`None`)
+fn parse_altitude(row: &str) -> Option<f32> {
+    row.split_once("}\",")?.1.split(',').next()?.parse().ok()
+}
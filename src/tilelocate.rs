@@ -1,12 +1,18 @@
 use coordinate_transformer::{ll2pixel, ZoomLv};
 use indicatif::ProgressBar;
 use rayon::prelude::*;
-use rustc_hash::FxBuildHasher;
+use rustc_hash::{FxBuildHasher, FxHashMap, FxHashSet};
+use clap::ValueEnum;
 use spade::{validate_vertex, DelaunayTriangulation, HasPosition, Point2, Triangulation};
-use std::collections::{HashMap, HashSet};
+use std::collections::HashSet;
 use std::fs::{canonicalize, OpenOptions};
 use std::io::{BufRead, BufReader, BufWriter, Write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use crate::basin::BASIN_ID_COLUMN;
+use crate::distance::DistanceMeasure;
+use crate::graph::{find_node_id_column, parse_node_id, RiverGraph, Table};
+use crate::TilelocateArgs;
 
 #[derive(Debug, Clone)]
 struct RiverNode {
@@ -31,6 +37,22 @@ impl HasPosition for RiverNode {
     }
 }
 
+/// ノードをタイルに所属させる方法
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum MembershipMode {
+    /// ノードが含まれるタイル
+    Node,
+    /// ノードを頂点とするドロネー三角形と重なるタイル
+    Triangle,
+    /// ノードを端点とするドロネー三角分割の辺が通るタイル
+    Link,
+    /// ノードを端点とするRIVER_LINKが通るタイル
+    River,
+}
+
+/// (RIVER_LINKの始点のノードID, 終点のノードID, リンクが通るタイル(X, Y))
+type RiverCrossing = (u64, u64, (u32, u32));
+
 /// 河川データのノードを読み込む
 fn read_nodes(nodes_path: PathBuf) -> Vec<RiverNode> {
     let file = OpenOptions::new()
@@ -70,10 +92,21 @@ fn read_nodes(nodes_path: PathBuf) -> Vec<RiverNode> {
         }).collect()
 }
 
-pub(crate) fn tile_locator(nodes_path: &String, max_zoomlv: ZoomLv) {
+/// `deterministic`が指定された場合は、ノードをID順に三角分割し、タイルを(z, x, y)順、所属をノードID順に書き出す
+/// `delaunay_links`が指定された場合は、三角分割の辺をNEARリンクとしてdelaunay_link.csvに書き出す
+pub(crate) fn tile_locator(args: &TilelocateArgs) {
     let spinner = ProgressBar::new_spinner();
     spinner.enable_steady_tick(std::time::Duration::from_millis(100));
 
+    let TilelocateArgs {
+        input: nodes_path,
+        max_zoomlv,
+        deterministic,
+        ..
+    } = args;
+    let max_zoomlv = ZoomLv::parse(*max_zoomlv).expect("Failed to parse the zoom level");
+    let deterministic = *deterministic;
+
     let nodes_path = canonicalize(nodes_path).expect("Failed to canonicalize the path");
     let tile_list_file = OpenOptions::new()
         .write(true)
@@ -98,63 +131,41 @@ pub(crate) fn tile_locator(nodes_path: &String, max_zoomlv: ZoomLv) {
     let mut tile_membership_file = BufWriter::new(tile_membership_file);
 
     spinner.set_message("Reading nodes...");
-    let nodes = read_nodes(nodes_path);
+    let mut nodes = read_nodes(nodes_path.clone());
+    if deterministic {
+        nodes.sort_by_key(|node| node.id);
+    }
 
     spinner.set_message("Calculating Delaunay triangulation...");
     let triangulation = DelaunayTriangulation::<RiverNode>::bulk_load(nodes).expect("Failed to create Delaunay triangulation");
 
+    let links_path = args
+        .links
+        .as_ref()
+        .map(|links| canonicalize(links).expect("Failed to canonicalize the path"))
+        .unwrap_or_else(|| nodes_path.with_file_name("river_link.csv"));
+
+    let crossings = if args.membership == MembershipMode::River || args.crosses {
+        spinner.set_message("Rasterizing river links...");
+        let mut crossings = river_crossings(&triangulation, &links_path, max_zoomlv);
+        if deterministic {
+            crossings.sort_unstable();
+        }
+        crossings
+    } else {
+        Vec::new()
+    };
+    if args.crosses {
+        write_crossings(&crossings, &nodes_path.with_file_name("river_tile_crossing.csv"), max_zoomlv);
+    }
 
-    // HashMap<(タイルX, タイルY), Vec<ノードID>>を作成
-    let mut tile_and_node = HashMap::<(u32, u32), Vec<u64>, FxBuildHasher>::with_hasher(FxBuildHasher::default());
-
-    // タイルに三角形がかぶっていたら、その三角形のノードIDを記録
-    triangulation.inner_faces().for_each(|face| {
-        let tri_vertices = face.vertices().map(|v| {
-            let ll_rad = (v.data().long.to_radians(), v.data().lat.to_radians());
-            ll2pixel(ll_rad, max_zoomlv)
-        });
-        let ids = face.vertices().map(|v| v.data().id);
-        // ピクセル座標のAABB
-        let (max_x, min_x, max_y, min_y) = {
-            let max_pixel = tri_vertices.iter().fold((0, 0), |acc, v| (acc.0.max(v.0), acc.1.max(v.1)));
-            let min_pixel = tri_vertices.iter().fold((u32::MAX, u32::MAX), |acc, v| (acc.0.min(v.0), acc.1.min(v.1)));
-
-            (max_pixel.0, min_pixel.0, max_pixel.1, min_pixel.1)
-        };
-
-        let check_tile_list = {
-            // タイル座標のAABB
-            let (max_tile_x, min_tile_x, max_tile_y, min_tile_y) = (max_x / 256, min_x / 256, max_y / 256, min_y / 256);
-
-            (min_tile_x..=max_tile_x)
-                .flat_map(move |x| (min_tile_y..=max_tile_y).map(move |y| (x, y)))
-        };
-
-        check_tile_list.for_each(|(tile_x, tile_y)| {
-            // タイルの4頂点のピクセル座標のリスト
-            let tile_aabb = [(tile_x, tile_y), (tile_x + 1, tile_y), (tile_x, tile_y + 1), (tile_x + 1, tile_y + 1)]
-                .map(|(x, y)| (x * 256, y * 256));
-
-            fn cross_product(p1: (u32, u32), p2: (u32, u32), p: (u32, u32)) -> i64 {
-                (p2.0 as i64 - p1.0 as i64) * (p.1 as i64 - p1.1 as i64) - (p2.1 as i64 - p1.1 as i64) * (p.0 as i64 - p1.0 as i64)
-            }
-
-            // タイル4頂点のうち、一つでも三角形の中にあれば、その三角形はタイルに含まれる
-            let is_contained = tile_aabb.iter().any(|p| {
-                let cross1 = cross_product(tri_vertices[0], tri_vertices[1], *p);
-                let cross2 = cross_product(tri_vertices[1], tri_vertices[2], *p);
-                let cross3 = cross_product(tri_vertices[2], tri_vertices[0], *p);
-
-                (cross1 >= 0 && cross2 >= 0 && cross3 >= 0) || (cross1 <= 0 && cross2 <= 0 && cross3 <= 0)
-            });
+    if args.delaunay_links {
+        spinner.set_message("Writing Delaunay links...");
+        write_delaunay_links(&triangulation, &nodes_path, &links_path, args);
+    }
 
-            if is_contained {
-                let entry = tile_and_node.entry((tile_x, tile_y)).or_default();
-                let ids = face.vertices().map(|v| v.data().id);
-                entry.extend(ids);
-            }
-        });
-    });
+    spinner.set_message("Locating nodes in tiles...");
+    let tile_and_node = tile_memberships(&triangulation, max_zoomlv, args.membership, &crossings);
 
     {
         // ヘッダーを書き込む
@@ -162,14 +173,20 @@ pub(crate) fn tile_locator(nodes_path: &String, max_zoomlv: ZoomLv) {
         tile_membership_file.write_all(buf.as_bytes()).expect("Failed to write header");
 
 
-        tile_and_node.iter().for_each(|(tile, nodes)| {
-            nodes.iter().for_each(|node| {
-                let tile_id = format!("{}-{}-{}", tile.0, tile.1, max_zoomlv as u32);
-                let node_id = node.to_string();
+        let mut memberships = tile_and_node
+            .iter()
+            .flat_map(|(tile, nodes)| nodes.iter().map(move |node| (*tile, *node)))
+            .collect::<Vec<_>>();
+        if deterministic {
+            memberships.sort_unstable();
+        }
 
-                let buf = [tile_id, node_id, "MEMBER".to_string()].join(",") + "\n";
-                tile_membership_file.write_all(buf.as_bytes()).expect("Failed to write edge");
-            });
+        memberships.iter().for_each(|(tile, node)| {
+            let tile_id = format!("{}-{}-{}", tile.0, tile.1, max_zoomlv as u32);
+            let node_id = node.to_string();
+
+            let buf = [tile_id, node_id, "MEMBER".to_string()].join(",") + "\n";
+            tile_membership_file.write_all(buf.as_bytes()).expect("Failed to write edge");
         });
 
         tile_membership_file.flush().expect("Failed to flush the file");
@@ -187,11 +204,15 @@ pub(crate) fn tile_locator(nodes_path: &String, max_zoomlv: ZoomLv) {
         let mut tiles = HashSet::<(u32, u32), FxBuildHasher>::from_iter(tile_and_node.keys().map(|(x, y)| (*x, *y)));
         let mut parent_tiles = HashSet::<(u32, u32), FxBuildHasher>::with_hasher(FxBuildHasher);
 
+        // (z, x, y)と書き出す行の組
+        let mut tile_rows = Vec::<((u32, u32, u32), String)>::new();
+        let mut family_rows = Vec::<((u32, u32, u32), String)>::new();
+
         tiles.iter().for_each(|(x, y)| {
             let tile_id = format!("{}-{}-{}", x, y, max_zoomlv as u32);
             let label = format!("Tile{}", max_zoomlv as u32);
             let buf = [tile_id, label, x.to_string(), y.to_string()].join(",") + "\n";
-            tiles_file.write_all(buf.as_bytes()).expect("Failed to write edge");
+            tile_rows.push(((max_zoomlv as u32, *x, *y), buf));
         });
 
         (1..=max_zoomlv as u32).rev().for_each(|zoom| {
@@ -203,18 +224,421 @@ pub(crate) fn tile_locator(nodes_path: &String, max_zoomlv: ZoomLv) {
                 parent_tiles.insert(parent_tile);
 
                 let buf = [parent_tile_id, tile_id, "CHILD".to_string()].join(",") + "\n";
-                tile_family_file.write_all(buf.as_bytes()).expect("Failed to write edge");
+                family_rows.push(((zoom, *x, *y), buf));
             });
 
             parent_tiles.iter().for_each(|(x, y)| {
                 let tile_id = format!("{}-{}-{}", x, y, zoom - 1);
                 let label = format!("Tile{}", zoom - 1);
                 let buf = [tile_id, label, x.to_string(), y.to_string()].join(",") + "\n";
-                tiles_file.write_all(buf.as_bytes()).expect("Failed to write edge");
+                tile_rows.push(((zoom - 1, *x, *y), buf));
             });
 
             tiles = parent_tiles.clone();
             parent_tiles.clear();
+        });
+
+        if deterministic {
+            tile_rows.sort_unstable();
+            family_rows.sort_unstable();
+        }
+
+        tile_rows.iter().for_each(|(_, buf)| {
+            tiles_file.write_all(buf.as_bytes()).expect("Failed to write edge");
+        });
+        family_rows.iter().for_each(|(_, buf)| {
+            tile_family_file.write_all(buf.as_bytes()).expect("Failed to write edge");
+        });
+
+        tiles_file.flush().expect("Failed to flush the file");
+        tile_family_file.flush().expect("Failed to flush the file");
+    }
+}
+
+/// タイル(X, Y)ごとに所属するノードIDを求める
+/// `crossings`は`MembershipMode::River`の場合に使うRIVER_LINKが通るタイル
+fn tile_memberships(
+    triangulation: &DelaunayTriangulation<RiverNode>,
+    max_zoomlv: ZoomLv,
+    mode: MembershipMode,
+    crossings: &[RiverCrossing],
+) -> FxHashMap<(u32, u32), FxHashSet<u64>> {
+    let mut tile_and_node = FxHashMap::<(u32, u32), FxHashSet<u64>>::default();
+    let pixel = |node: &RiverNode| to_subpixel(ll2pixel((node.long.to_radians(), node.lat.to_radians()), max_zoomlv));
+
+    match mode {
+        MembershipMode::Node => triangulation.vertices().for_each(|v| {
+            let (x, y) = pixel(v.data());
+            tile_and_node.entry(((x / 512) as u32, (y / 512) as u32)).or_default().insert(v.data().id);
+        }),
+        MembershipMode::Triangle => triangulation.inner_faces().for_each(|face| {
+            let ids = face.vertices().map(|v| v.data().id);
+            let triangle = face.vertices().map(|v| pixel(v.data()));
+            for tile in candidate_tiles(&triangle) {
+                if triangle_intersects_rect(triangle, tile_rect(tile)) {
+                    tile_and_node.entry(tile).or_default().extend(ids);
+                }
+            }
+        }),
+        MembershipMode::Link => triangulation.undirected_edges().for_each(|edge| {
+            let [v1, v2] = edge.vertices();
+            let (v1, v2) = (v1.data(), v2.data());
+            for tile in segment_tiles(pixel(v1), pixel(v2)) {
+                tile_and_node.entry(tile).or_default().extend([v1.id, v2.id]);
+            }
+        }),
+        MembershipMode::River => crossings.iter().for_each(|(start, end, tile)| {
+            tile_and_node.entry(*tile).or_default().extend([*start, *end]);
+        }),
+    }
+
+    tile_and_node
+}
+
+/// river_link.csvのRIVER_LINKごとに、リンクが通るタイルを求める
+fn river_crossings(
+    triangulation: &DelaunayTriangulation<RiverNode>,
+    links_path: &Path,
+    max_zoomlv: ZoomLv,
+) -> Vec<RiverCrossing> {
+    let pixels = triangulation
+        .vertices()
+        .map(|v| {
+            let node = v.data();
+            let pixel = ll2pixel((node.long.to_radians(), node.lat.to_radians()), max_zoomlv);
+            (node.id, to_subpixel(pixel))
+        })
+        .collect::<FxHashMap<_, _>>();
+    let pixel = |id: u64| {
+        *pixels
+            .get(&id)
+            .unwrap_or_else(|| panic!("Node {} of a river link not found in the nodes", id))
+    };
+
+    let links = Table::read(links_path);
+    let start_column = links.expect_column(":START_ID");
+    let end_column = links.expect_column(":END_ID");
+
+    links
+        .rows
+        .par_iter()
+        .flat_map_iter(|row| {
+            let start = parse_node_id(&row[start_column]) as u64;
+            let end = parse_node_id(&row[end_column]) as u64;
+            segment_tiles(pixel(start), pixel(end)).map(move |tile| (start, end, tile))
+        })
+        .collect()
+}
+
+/// RIVER_LINKが通るタイルを、始点のノードからタイルへのCROSSESリレーションシップとして書き出す
+fn write_crossings(crossings: &[RiverCrossing], path: &Path, max_zoomlv: ZoomLv) {
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(path)
+        .unwrap();
+    let mut file = BufWriter::new(file);
+    let buf = [":START_ID", ":END_ID", ":TYPE", "link_end_id:long"].join(",") + "\n";
+    file.write_all(buf.as_bytes()).expect("Failed to write header");
+    crossings.iter().for_each(|(start, end, tile)| {
+        let buf = format!("{},{}-{}-{},CROSSES,{}\n", start, tile.0, tile.1, max_zoomlv as u32, end);
+        file.write_all(buf.as_bytes()).expect("Failed to write edge");
+    });
+    file.flush().expect("Failed to flush the file");
+}
+
+/// ピクセル座標を、ピクセルの中心が奇数になる2倍の座標に変換する
+/// タイル(X, Y)は[512X, 512(X + 1)] x [512Y, 512(Y + 1)]の範囲になり、ノードがタイルの境界上に載らない
+fn to_subpixel((x, y): (u32, u32)) -> (i64, i64) {
+    (x as i64 * 2 + 1, y as i64 * 2 + 1)
+}
+
+/// タイルの範囲 (最小X, 最小Y, 最大X, 最大Y)
+fn tile_rect((x, y): (u32, u32)) -> (i64, i64, i64, i64) {
+    let (x, y) = (x as i64 * 512, y as i64 * 512);
+    (x, y, x + 512, y + 512)
+}
+
+/// 点を囲む範囲に含まれるタイル
+fn candidate_tiles(points: &[(i64, i64)]) -> impl Iterator<Item = (u32, u32)> {
+    let min_x = points.iter().map(|p| p.0).min().unwrap() / 512;
+    let max_x = points.iter().map(|p| p.0).max().unwrap() / 512;
+    let min_y = points.iter().map(|p| p.1).min().unwrap() / 512;
+    let max_y = points.iter().map(|p| p.1).max().unwrap() / 512;
+    (min_x..=max_x).flat_map(move |x| (min_y..=max_y).map(move |y| (x as u32, y as u32)))
+}
+
+/// 線分が通るタイル
+fn segment_tiles(p1: (i64, i64), p2: (i64, i64)) -> impl Iterator<Item = (u32, u32)> {
+    candidate_tiles(&[p1, p2]).filter(move |tile| segment_intersects_rect(p1, p2, tile_rect(*tile)))
+}
+
+/// p1からp2へのベクトルとp1からpへのベクトルの外積
+fn cross_product(p1: (i64, i64), p2: (i64, i64), p: (i64, i64)) -> i64 {
+    (p2.0 - p1.0) * (p.1 - p1.1) - (p2.1 - p1.1) * (p.0 - p1.0)
+}
+
+/// 長方形の4頂点
+fn rect_corners((min_x, min_y, max_x, max_y): (i64, i64, i64, i64)) -> [(i64, i64); 4] {
+    [(min_x, min_y), (max_x, min_y), (min_x, max_y), (max_x, max_y)]
+}
+
+/// 点を囲む範囲と長方形が重なるか (境界を含む)
+fn bounds_overlap(points: &[(i64, i64)], (min_x, min_y, max_x, max_y): (i64, i64, i64, i64)) -> bool {
+    points.iter().any(|p| p.0 >= min_x)
+        && points.iter().any(|p| p.0 <= max_x)
+        && points.iter().any(|p| p.1 >= min_y)
+        && points.iter().any(|p| p.1 <= max_y)
+}
+
+/// 線分と長方形が交差するか (境界を含む)
+/// 分離軸定理により、座標軸と線分の法線のいずれにも分離されなければ交差する
+fn segment_intersects_rect(p1: (i64, i64), p2: (i64, i64), rect: (i64, i64, i64, i64)) -> bool {
+    if !bounds_overlap(&[p1, p2], rect) {
+        return false;
+    }
+    let sides = rect_corners(rect).map(|p| cross_product(p1, p2, p));
+    !(sides.iter().all(|side| *side > 0) || sides.iter().all(|side| *side < 0))
+}
+
+/// 三角形と長方形が交差するか (境界を含む)
+/// 分離軸定理により、座標軸と三角形の各辺の法線のいずれにも分離されなければ交差する
+fn triangle_intersects_rect(triangle: [(i64, i64); 3], rect: (i64, i64, i64, i64)) -> bool {
+    if !bounds_overlap(&triangle, rect) {
+        return false;
+    }
+
+    let area = cross_product(triangle[0], triangle[1], triangle[2]);
+    // ピクセルに丸めて潰れた三角形は、辺のいずれかと交差するかで判定する
+    if area == 0 {
+        return (0..3).any(|i| segment_intersects_rect(triangle[i], triangle[(i + 1) % 3], rect));
+    }
+
+    // 長方形の4頂点がすべて三角形の辺の外側にあれば分離されている
+    let corners = rect_corners(rect);
+    (0..3).all(|i| {
+        let (p1, p2) = (triangle[i], triangle[(i + 1) % 3]);
+        corners.iter().any(|p| cross_product(p1, p2, *p).signum() * area.signum() >= 0)
+    })
+}
+
+/// 三角分割の辺をNEARリンクとしてdelaunay_link.csvに書き出す
+/// 始点は終点よりIDの小さいノードとし、`--near-max-length`より長い辺と、`--near-different-rivers`の場合は同じ水系のノードを結ぶ辺を除く
+fn write_delaunay_links(
+    triangulation: &DelaunayTriangulation<RiverNode>,
+    nodes_path: &Path,
+    links_path: &Path,
+    args: &TilelocateArgs,
+) {
+    let distance = DistanceMeasure::new(&args.distance);
+    let rivers = args.near_different_rivers.then(|| read_rivers(nodes_path, links_path));
+
+    let mut links = triangulation
+        .undirected_edges()
+        .filter_map(|edge| {
+            let [v1, v2] = edge.vertices();
+            let (v1, v2) = (v1.data(), v2.data());
+            let (start, end) = if v1.id <= v2.id { (v1, v2) } else { (v2, v1) };
+            if let Some(rivers) = &rivers {
+                // 水系が分からないノードを結ぶ辺も除く
+                match (rivers.get(&start.id), rivers.get(&end.id)) {
+                    (Some(river1), Some(river2)) if river1 != river2 => {}
+                    _ => return None,
+                }
+            }
+
+            let length = distance.distance_m((start.long, start.lat), (end.long, end.lat));
+            if args.near_max_length.is_some_and(|max_length| length > max_length) {
+                return None;
+            }
+            Some((start.id, end.id, length))
         })
+        .collect::<Vec<_>>();
+    if args.deterministic {
+        links.sort_unstable_by_key(|(start, end, _)| (*start, *end));
+    }
+
+    let file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(nodes_path.with_file_name("delaunay_link.csv"))
+        .unwrap();
+    let mut file = BufWriter::new(file);
+    let buf = [":START_ID", ":END_ID", ":TYPE", "length:float"].join(",") + "\n";
+    file.write_all(buf.as_bytes()).expect("Failed to write header");
+    links.iter().for_each(|(start, end, length)| {
+        let buf = format!("{},{},NEAR,{:.3}\n", start, end, length);
+        file.write_all(buf.as_bytes()).expect("Failed to write edge");
+    });
+    file.flush().expect("Failed to flush the file");
+}
+
+/// ノードIDごとの水系
+/// ノードに`basin_id:long`があればそれを使い、無い場合はriver_link.csvで繋がったノードを同じ水系とする
+fn read_rivers(nodes_path: &Path, links_path: &Path) -> FxHashMap<u64, usize> {
+    let nodes = Table::read(nodes_path);
+    let id_column = find_node_id_column(&nodes);
+
+    if let Some(basin_column) = nodes.column(BASIN_ID_COLUMN) {
+        return nodes
+            .rows
+            .iter()
+            .filter(|row| !row[basin_column].is_empty())
+            .map(|row| {
+                let basin = row[basin_column]
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Failed to parse basin_id: {}", row[basin_column]));
+                (parse_node_id(&row[id_column]) as u64, basin)
+            })
+            .collect();
+    }
+
+    let links = Table::read(links_path);
+    let graph = RiverGraph::new(&nodes, &links);
+    let (component, _) = graph.components();
+    graph
+        .ids
+        .iter()
+        .zip(component)
+        .map(|(id, component)| (*id as u64, component))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use coordinate_transformer::pixel2ll;
+
+    use super::*;
+
+    /// タイル(0, 0)の範囲
+    const TILE: (i64, i64, i64, i64) = (0, 0, 512, 512);
+
+    #[test]
+    fn small_triangle_inside_tile() {
+        let triangle = [(101, 101), (201, 101), (151, 201)];
+        assert!(triangle_intersects_rect(triangle, TILE));
+        assert!(!triangle_intersects_rect(triangle, tile_rect((1, 0))));
+    }
+
+    #[test]
+    fn triangle_crossing_edge_without_corner() {
+        // タイル(0, 0)と(1, 0)の境界をまたぎ、どちらのタイルの頂点も含まない
+        let triangle = [(401, 201), (601, 221), (451, 301)];
+        assert!(triangle_intersects_rect(triangle, TILE));
+        assert!(triangle_intersects_rect(triangle, tile_rect((1, 0))));
+        assert!(!triangle_intersects_rect(triangle, tile_rect((0, 1))));
+        assert!(!triangle_intersects_rect(triangle, tile_rect((1, 1))));
+    }
+
+    #[test]
+    fn triangle_covering_corner() {
+        let triangle = [(-99, -99), (1001, -99), (-99, 1001)];
+        assert!(triangle_intersects_rect(triangle, TILE));
+    }
+
+    #[test]
+    fn triangle_separated_by_edge() {
+        // 範囲はタイル(1, 1)と重なるが、斜辺で分離されている
+        let triangle = [(-99, -99), (1001, -99), (-99, 1001)];
+        assert!(!triangle_intersects_rect(triangle, tile_rect((1, 1))));
+    }
+
+    #[test]
+    fn triangle_touching_corner() {
+        // 頂点(512, 512)で接する三角形も重なるものとする
+        let triangle = [(511, 513), (513, 511), (1001, 1001)];
+        assert!(triangle_intersects_rect(triangle, TILE));
+        assert!(!triangle_intersects_rect([(515, 513), (513, 515), (1001, 1001)], TILE));
+    }
+
+    #[test]
+    fn degenerate_triangle() {
+        let triangle = [(-99, 101), (301, 101), (701, 101)];
+        assert!(triangle_intersects_rect(triangle, TILE));
+        assert!(triangle_intersects_rect(triangle, tile_rect((1, 0))));
+        assert!(!triangle_intersects_rect(triangle, tile_rect((0, 1))));
+    }
+
+    #[test]
+    fn segment_crossing_tile() {
+        // 端点はどちらもタイルの外にある
+        assert!(segment_intersects_rect((-99, 101), (601, 301), TILE));
+        // 範囲は重なるが、角の外側を通る
+        assert!(!segment_intersects_rect((451, 601), (601, 451), TILE));
+        assert!(segment_intersects_rect((451, 601), (601, 451), tile_rect((1, 1))));
+    }
+
+    #[test]
+    fn tiles_along_segment() {
+        // タイル(0, 0)から(2, 1)へ斜めに進み、(1, 0)と(1, 1)を通る
+        let mut tiles = segment_tiles((101, 301), (1201, 701)).collect::<Vec<_>>();
+        tiles.sort_unstable();
+        assert_eq!(tiles, vec![(0, 0), (1, 0), (1, 1), (2, 1)]);
+
+        // 同じタイル内の線分
+        assert_eq!(segment_tiles((101, 101), (301, 401)).collect::<Vec<_>>(), vec![(0, 0)]);
+    }
+
+    #[test]
+    fn memberships_from_river_crossings() {
+        let zoom = ZoomLv::parse(15).unwrap();
+        let triangulation = DelaunayTriangulation::<RiverNode>::new();
+        let crossings = [(1, 2, (0, 0)), (1, 2, (1, 0)), (2, 3, (1, 0))];
+
+        let memberships = tile_memberships(&triangulation, zoom, MembershipMode::River, &crossings);
+        assert_eq!(memberships[&(0, 0)], FxHashSet::from_iter([1, 2]));
+        assert_eq!(memberships[&(1, 0)], FxHashSet::from_iter([1, 2, 3]));
+    }
+
+    /// タイル(x, y)内の(dx, dy)ピクセル目の中心にあるノード
+    fn node_in_tile(id: u64, (x, y): (u32, u32), (dx, dy): (u32, u32), zoom: ZoomLv) -> RiverNode {
+        let (long, lat) = pixel2ll((x * 256 + dx, y * 256 + dy), zoom);
+        RiverNode::new(id, long.to_degrees() + 1e-9, lat.to_degrees() - 1e-9)
+    }
+
+    #[test]
+    fn memberships_of_nodes_within_one_tile() {
+        let zoom = ZoomLv::parse(15).unwrap();
+        let tile = (28672, 13004);
+        let nodes = [(1, (50, 50)), (2, (150, 60)), (3, (100, 150))]
+            .map(|(id, pixel)| node_in_tile(id, tile, pixel, zoom))
+            .to_vec();
+        let triangulation = DelaunayTriangulation::<RiverNode>::bulk_load(nodes).unwrap();
+
+        for mode in [MembershipMode::Node, MembershipMode::Triangle, MembershipMode::Link] {
+            let memberships = tile_memberships(&triangulation, zoom, mode, &[]);
+            assert_eq!(memberships.len(), 1, "{:?}", mode);
+            assert_eq!(memberships[&tile], FxHashSet::from_iter([1, 2, 3]), "{:?}", mode);
+        }
+    }
+
+    #[test]
+    fn memberships_of_triangle_crossing_tiles() {
+        let zoom = ZoomLv::parse(15).unwrap();
+        let tile = (28672, 13004);
+        let right = (tile.0 + 1, tile.1);
+        // ノード1, 2はタイルの右端付近、ノード3は右隣のタイルにある
+        let nodes = vec![
+            node_in_tile(1, tile, (200, 100), zoom),
+            node_in_tile(2, tile, (220, 140), zoom),
+            node_in_tile(3, right, (40, 110), zoom),
+        ];
+        let triangulation = DelaunayTriangulation::<RiverNode>::bulk_load(nodes).unwrap();
+
+        let memberships = tile_memberships(&triangulation, zoom, MembershipMode::Node, &[]);
+        assert_eq!(memberships[&tile], FxHashSet::from_iter([1, 2]));
+        assert_eq!(memberships[&right], FxHashSet::from_iter([3]));
+
+        let memberships = tile_memberships(&triangulation, zoom, MembershipMode::Triangle, &[]);
+        assert_eq!(memberships.len(), 2);
+        assert_eq!(memberships[&tile], FxHashSet::from_iter([1, 2, 3]));
+        assert_eq!(memberships[&right], FxHashSet::from_iter([1, 2, 3]));
+
+        // 辺1-3と辺2-3が両方のタイルを通る
+        let memberships = tile_memberships(&triangulation, zoom, MembershipMode::Link, &[]);
+        assert_eq!(memberships[&tile], FxHashSet::from_iter([1, 2, 3]));
+        assert_eq!(memberships[&right], FxHashSet::from_iter([1, 2, 3]));
     }
 }
@@ -0,0 +1,416 @@
+use std::fs::canonicalize;
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use rustc_hash::{FxHashMap, FxHashSet};
+
+use crate::collect::{
+    check_link_header, check_nodes_header, link_row, node_row, read_tile_list, sort_links, CollectedBatch, Collector,
+    SNAPSHOT_FILE_NAME, STITCH_REPORT_FILE_NAME, TILE_INDEX_HEADER,
+};
+use crate::mokuroku::{download_mokuroku, read_mokuroku, write_mokuroku, TileEntry};
+use crate::stitch::stitch_tiles;
+use crate::CollectArgs;
+
+/// 前回の実行時と今回のmokurokuの差分
+struct TileDiff {
+    added: Vec<String>,
+    modified: Vec<String>,
+    removed: Vec<String>,
+}
+
+impl TileDiff {
+    fn new(previous: &[TileEntry], current: &[TileEntry]) -> Self {
+        let previous_map = previous
+            .iter()
+            .map(|entry| (entry.path.as_str(), entry))
+            .collect::<FxHashMap<_, _>>();
+        let current_paths = current
+            .iter()
+            .map(|entry| entry.path.as_str())
+            .collect::<FxHashSet<_>>();
+
+        let mut added = Vec::new();
+        let mut modified = Vec::new();
+        for entry in current {
+            match previous_map.get(entry.path.as_str()) {
+                None => added.push(entry.path.clone()),
+                Some(prev) if prev.is_modified(entry) => modified.push(entry.path.clone()),
+                Some(_) => {}
+            }
+        }
+
+        let removed = previous
+            .iter()
+            .filter(|entry| !current_paths.contains(entry.path.as_str()))
+            .map(|entry| entry.path.clone())
+            .collect();
+
+        Self {
+            added,
+            modified,
+            removed,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.modified.is_empty() && self.removed.is_empty()
+    }
+}
+
+/// collectサブコマンドの`--update`用の関数
+/// 前回の実行時に保存したmokurokuと比較し、追加・変更・削除されたタイルに関わるノードとリンクのみを書き換える
+pub async fn update_river_data(args: &CollectArgs) {
+    let spinner = ProgressBar::new_spinner();
+    spinner.set_message("Initializing...");
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    let CollectArgs {
+        mokuroku,
+        fetch_mokuroku,
+        batch: batch_size,
+        river_base_url,
+        stitch_tolerance,
+        deterministic,
+        ..
+    } = args;
+    if *fetch_mokuroku {
+        spinner.set_message("Fetching mokuroku.csv.gz...");
+        download_mokuroku(river_base_url, Path::new(mokuroku)).await;
+    }
+    let mokuroku = canonicalize(mokuroku).expect("Failed to canonicalize mokuroku file path");
+
+    let nodes_path = mokuroku.with_file_name("river_node.csv");
+    let links_path = mokuroku.with_file_name("river_link.csv");
+    let index_path = mokuroku.with_file_name("river_tile_index.csv");
+    let snapshot_path = mokuroku.with_file_name(SNAPSHOT_FILE_NAME);
+    let changeset_path = mokuroku.with_file_name("river_changeset.csv");
+    let collision_path = mokuroku.with_file_name("river_node_collision.csv");
+
+    if !snapshot_path.exists() {
+        panic!(
+            "No snapshot of the previous run found at {:?}. Run collect without --update first.",
+            snapshot_path
+        );
+    }
+
+    spinner.set_message("Comparing mokuroku with the previous run...");
+    let collector = Collector::new(args);
+    check_nodes_header(&nodes_path, collector.id_zoom);
+    check_link_header(&links_path, &collector.property_keys);
+    let current = read_tile_list(&mokuroku, collector.region.as_deref());
+    let previous = read_mokuroku(&snapshot_path);
+    let diff = TileDiff::new(&previous, &current);
+
+    if diff.is_empty() {
+        spinner.finish_with_message("No tiles have changed since the previous run.");
+        return;
+    }
+
+    spinner.finish_and_clear();
+
+    // 追加・変更されたタイルを取得し直す
+    let refetch = diff
+        .added
+        .iter()
+        .chain(&diff.modified)
+        .cloned()
+        .collect::<Vec<_>>();
+
+    let pb = ProgressBar::new(refetch.len() as u64);
+    pb.set_message(format!(
+        "Refetching tiles ({} added, {} modified, {} removed)...",
+        diff.added.len(),
+        diff.modified.len(),
+        diff.removed.len()
+    ));
+    pb.set_style(
+        ProgressStyle::with_template("{msg}\n[{elapsed_precise}] {wide_bar} {pos}/{len} ({eta_precise})")
+            .unwrap(),
+    );
+
+    let mut new_node_rows = FxHashMap::<usize, Vec<String>>::default();
+    let mut new_links = Vec::<((usize, usize), String)>::new();
+    let mut new_index_rows = Vec::new();
+    let mut failed_tiles = FxHashSet::default();
+    for batch in refetch.chunks(*batch_size) {
+        let CollectedBatch {
+            nodes,
+            links,
+            index,
+            failed,
+        } = collector.process(batch).await;
+
+        for node in nodes {
+            let row = node_row(&node).trim_end().to_string();
+            let rows = new_node_rows.entry(node.0).or_default();
+            if !rows.contains(&row) {
+                rows.push(row);
+            }
+        }
+        new_links.extend(links.iter().map(|link| ((link.0, link.1), link_row(link))));
+        new_index_rows.extend(index.iter().map(|(tile, id1, id2)| format!("{tile},{id1},{id2}")));
+        failed_tiles.extend(failed);
+
+        pb.inc(batch.len() as u64);
+    }
+    pb.finish_and_clear();
+    if !failed_tiles.is_empty() {
+        eprintln!(
+            "Failed to fetch {} tiles. Their previous data is kept and they will be retried on the next update.",
+            failed_tiles.len()
+        );
+    }
+
+    let spinner = ProgressBar::new_spinner();
+    spinner.enable_steady_tick(std::time::Duration::from_millis(100));
+
+    // 取得し直したノードの重複削除
+    // 同じIDに異なる座標・標高のノードがある場合はそれらを衝突として書き出し、座標・標高の順で先頭の行を残す
+    let mut collision_rows = Vec::new();
+    let mut new_nodes = FxHashMap::<usize, String>::default();
+    for (id, mut rows) in new_node_rows {
+        rows.sort_unstable();
+        if rows.len() > 1 {
+            collision_rows.extend(rows.iter().cloned());
+        }
+        new_nodes.insert(id, rows.swap_remove(0));
+    }
+    collision_rows.sort_by_cached_key(|line| (parse_id(line.split(',').next()), line.clone()));
+
+    // 取得できなかったタイルは変更が無かったものとして扱い、既存の行を残す
+    let affected = diff
+        .added
+        .iter()
+        .chain(&diff.modified)
+        .chain(&diff.removed)
+        .map(String::as_str)
+        .filter(|tile| !failed_tiles.contains(*tile))
+        .collect::<FxHashSet<_>>();
+
+    // 変更の無いタイルに由来するリンクを残す
+    spinner.set_message("Reading river_tile_index.csv...");
+    let mut index_rows = Vec::new();
+    let mut kept_links = FxHashSet::default();
+    let mut dropped_links = FxHashSet::default();
+    for line in read_rows(&index_path).1 {
+        let mut iter = line.split(',');
+        let tile = iter.next().unwrap_or_default();
+        let key = parse_link_key(iter);
+        if affected.contains(tile) {
+            dropped_links.insert(key);
+        } else {
+            kept_links.insert(key);
+            index_rows.push(line);
+        }
+    }
+    index_rows.extend(new_index_rows);
+
+    spinner.set_message("Rewriting nodes and links...");
+
+    // リンクの更新
+    // 変更の無いタイルのリンクは既存の行をそのまま残し、取得し直したタイルのリンクを追加する
+    let (links_header, old_link_rows) = read_rows(&links_path);
+    let mut old_links = FxHashSet::default();
+    let mut final_links = FxHashSet::default();
+    let mut link_rows = Vec::new();
+    for line in old_link_rows {
+        let key = parse_link_key(line.split(','));
+        old_links.insert(key);
+        let kept = !dropped_links.contains(&key) || kept_links.contains(&key);
+        if kept && final_links.insert(key) {
+            link_rows.push(line);
+        }
+    }
+    for (key, row) in new_links {
+        if final_links.insert(key) {
+            link_rows.push(row.trim_end().to_string());
+        }
+    }
+
+    // ノードの更新
+    // 削除されたリンクの端点のうち、どのリンクからも参照されなくなったノードを削除する
+    let referenced = final_links
+        .iter()
+        .flat_map(|(id1, id2)| [*id1, *id2])
+        .chain(new_nodes.keys().copied())
+        .collect::<FxHashSet<_>>();
+    let removable = old_links
+        .difference(&final_links)
+        .flat_map(|(id1, id2)| [*id1, *id2])
+        .filter(|id| !referenced.contains(id))
+        .collect::<FxHashSet<_>>();
+
+    // 取得し直したノードは、座標や標高が変わっていることがあるため既存の行を置き換える
+    let (nodes_header, old_node_rows) = read_rows(&nodes_path);
+    let mut old_nodes = FxHashSet::default();
+    let mut final_nodes = FxHashSet::default();
+    let mut node_rows = Vec::new();
+    let mut bound_rows = Vec::new();
+    for line in old_node_rows {
+        if is_bound_row(&line) {
+            bound_rows.push(line);
+            continue;
+        }
+        let id = parse_id(line.split(',').next());
+        old_nodes.insert(id);
+        if !removable.contains(&id) && !new_nodes.contains_key(&id) && final_nodes.insert(id) {
+            node_rows.push(line);
+        }
+    }
+    for (id, row) in new_nodes {
+        if final_nodes.insert(id) {
+            node_rows.push(row);
+        }
+    }
+    if *deterministic {
+        node_rows.sort_by_cached_key(|line| parse_id(line.split(',').next()));
+    }
+    node_rows.extend(bound_rows);
+
+    write_rows(&nodes_path, &nodes_header, &node_rows);
+    write_rows(&collision_path, &nodes_header, &collision_rows);
+    if !collision_rows.is_empty() {
+        eprintln!(
+            "{} nodes with different coordinates or altitudes share an ID. See {:?}",
+            collision_rows.len(),
+            collision_path
+        );
+    }
+    write_rows(&links_path, &links_header, &link_rows);
+    write_rows(&index_path, TILE_INDEX_HEADER.trim_end(), &index_rows);
+
+    // 取得し直したタイルと周囲のタイルの中心線を接続する
+    if *stitch_tolerance > 0. {
+        spinner.set_message("Stitching lines across tile boundaries...");
+        let stitched = stitch_tiles(
+            &nodes_path,
+            &links_path,
+            &index_path,
+            &mokuroku.with_file_name(STITCH_REPORT_FILE_NAME),
+            *stitch_tolerance,
+            &collector.distance,
+        );
+        if stitched > 0 {
+            final_nodes = read_rows(&nodes_path)
+                .1
+                .iter()
+                .filter(|line| !is_bound_row(line))
+                .map(|line| parse_id(line.split(',').next()))
+                .collect();
+            final_links = read_rows(&links_path)
+                .1
+                .iter()
+                .map(|line| parse_link_key(line.split(',')))
+                .collect();
+        }
+    }
+
+    if *deterministic {
+        sort_links(&links_path, &index_path);
+    }
+
+    // 変更内容の書き出し
+    spinner.set_message("Writing changeset...");
+    let changeset = [
+        ("added", "node", diff_ids(&final_nodes, &old_nodes)),
+        ("removed", "node", diff_ids(&old_nodes, &final_nodes)),
+    ]
+        .into_iter()
+        .flat_map(|(change, kind, ids)| {
+            ids.into_iter()
+                .map(move |id| format!("{change},{kind},{id},"))
+        })
+        .chain(
+            [
+                ("added", final_links.difference(&old_links)),
+                ("removed", old_links.difference(&final_links)),
+            ]
+                .into_iter()
+                .flat_map(|(change, keys)| {
+                    let mut keys = keys.copied().collect::<Vec<_>>();
+                    keys.sort_unstable();
+                    keys.into_iter()
+                        .map(move |(id1, id2)| format!("{change},link,{id1},{id2}"))
+                }),
+        )
+        .collect::<Vec<_>>();
+    write_rows(&changeset_path, "change,kind,start_id,end_id", &changeset);
+
+    // 取得できなかったタイルは前回の内容を記録し、次回の更新で取得し直す
+    let previous_map = previous
+        .iter()
+        .map(|entry| (entry.path.as_str(), entry))
+        .collect::<FxHashMap<_, _>>();
+    let snapshot = current
+        .iter()
+        .filter_map(|entry| {
+            if failed_tiles.contains(&entry.path) {
+                previous_map.get(entry.path.as_str()).map(|prev| (*prev).clone())
+            } else {
+                Some(entry.clone())
+            }
+        })
+        .collect::<Vec<_>>();
+    write_mokuroku(&snapshot_path, &snapshot);
+    spinner.finish_with_message(format!(
+        "Update completed! {} changes written to {:?}",
+        changeset.len(),
+        changeset_path
+    ));
+}
+
+/// CSVファイルをヘッダーと各行に分けて読み込む
+pub(crate) fn read_rows(path: &Path) -> (String, Vec<String>) {
+    let file = std::fs::File::open(path)
+        .unwrap_or_else(|e| panic!("Failed to open {:?}: {:#?}", path, e));
+    let mut lines = BufReader::new(file)
+        .lines()
+        .map(|line| line.unwrap_or_else(|e| panic!("Failed to read {:?}: {:#?}", path, e)));
+
+    let header = lines.next().unwrap_or_default();
+    let rows = lines.filter(|line| !line.is_empty()).collect();
+
+    (header, rows)
+}
+
+/// ヘッダーと各行をCSVファイルに書き込む
+pub(crate) fn write_rows(path: &Path, header: &str, rows: &[String]) {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .unwrap_or_else(|e| panic!("Failed to create {:?}: {:#?}", path, e));
+    let mut buf = BufWriter::new(file);
+
+    for line in std::iter::once(header).chain(rows.iter().map(String::as_str)) {
+        buf.write_all(line.as_bytes())
+            .and_then(|_| buf.write_all(b"\n"))
+            .unwrap_or_else(|e| panic!("Failed to write {:?}: {:#?}", path, e));
+    }
+    buf.flush()
+        .unwrap_or_else(|e| panic!("Failed to flush {:?}: {:#?}", path, e));
+}
+
+/// BoundNodeの行か (後処理で列が追加されていても判定できるよう、全ての列を調べる)
+fn is_bound_row(line: &str) -> bool {
+    line.split(',').any(|field| field == "BoundNode")
+}
+
+pub(crate) fn parse_id(s: Option<&str>) -> usize {
+    s.and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| panic!("Failed to parse node ID: {:?}", s))
+}
+
+pub(crate) fn parse_link_key<'a>(mut iter: impl Iterator<Item = &'a str>) -> (usize, usize) {
+    (parse_id(iter.next()), parse_id(iter.next()))
+}
+
+/// `a`にあって`b`に無いIDを昇順で返す
+fn diff_ids(a: &FxHashSet<usize>, b: &FxHashSet<usize>) -> Vec<usize> {
+    let mut ids = a.difference(b).copied().collect::<Vec<_>>();
+    ids.sort_unstable();
+    ids
+}